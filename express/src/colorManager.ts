@@ -31,6 +31,9 @@ export interface NowChange {
 	now: string,
 	change_action: ChangeActionColor | "none",
 	just_updated: boolean,
+	override_id?: number | null,
+	paused?: boolean,
+	paused_until?: string | null,
 }
 
 const mirekBrightnessSchema = Joi.object<MirekBrightness>({
@@ -44,6 +47,9 @@ export const nowChangeSchema = Joi.object<NowChange>({
 	now: Joi.string().required(),
 	change_action: Joi.alternatives(Joi.string(), changeActionColorSchema).required(),
 	just_updated: Joi.bool().required(),
+	override_id: Joi.number().allow(null),
+	paused: Joi.bool(),
+	paused_until: Joi.string().allow(null),
 });
 
 async function getNowChange(): Promise<NowChange> {
@@ -0,0 +1,89 @@
+//! Changes to the schedule that both the HTTP routes and the WebSocket control channel make, so they
+//! notify subscribers and land in the audit log the same way whichever one asked.
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+
+use crate::{
+    audit::{AuditEntry, AuditEvent, AuditLog, Caller},
+    events::ChangeReason,
+    homes::Home,
+    overrides::{Override, OverrideRequest},
+    pause::Pause,
+};
+
+pub struct Actions<'a> {
+    pub caller: &'a Caller,
+    pub home: &'a Home,
+    pub audit: &'a AuditLog,
+}
+
+impl Actions<'_> {
+    fn entry(&self, now: DateTime<Tz>, event: AuditEvent) -> AuditEntry {
+        AuditEntry::new(now, event, self.caller).with_home(&self.home.id)
+    }
+
+    /// Recomputes today's schedule.
+    pub async fn force_update(&self) -> anyhow::Result<()> {
+        let mut guard = self.home.schedule.lock().await;
+        let now = (*guard).now();
+        (*guard).set_today(&now)?;
+        self.home.events.notify(ChangeReason::ForceUpdate);
+        self.audit.record(self.entry(now, AuditEvent::ForceUpdate));
+        Ok(())
+    }
+
+    pub async fn add_override(&self, request: &OverrideRequest) -> anyhow::Result<Override> {
+        let mut guard = self.home.schedule.lock().await;
+        let now = (*guard).now();
+        let added = (*guard).add_override(request, now)?;
+        self.home.events.notify(ChangeReason::Override);
+        self.audit.record(self.entry(now, AuditEvent::Override)
+            .with_action(added.change_action.clone())
+            .with_detail(format!("override {} until {}", added.id, added.expires)));
+        Ok(added)
+    }
+
+    /// Cancels one override, or all of them when `id` is `None`, returning how many were cancelled.
+    pub async fn cancel_overrides(&self, id: Option<u64>) -> anyhow::Result<usize> {
+        let mut guard = self.home.schedule.lock().await;
+        let now = (*guard).now();
+        let cancelled = (*guard).cancel_overrides(id);
+        if let (0, Some(id)) = (cancelled, id) {
+            return Err(anyhow::anyhow!("No override with id {id}."));
+        }
+        self.home.events.notify(ChangeReason::Override);
+        self.audit.record(self.entry(now, AuditEvent::CancelOverride).with_detail(match id {
+            Some(id) => format!("override {id}"),
+            None => format!("{cancelled} override(s)"),
+        }));
+        Ok(cancelled)
+    }
+
+    /// Pauses for `minutes`, or until resumed. `by` defaults to the caller.
+    pub async fn pause(&self, minutes: Option<u32>, by: Option<String>) -> anyhow::Result<Pause> {
+        let by = by.unwrap_or_else(|| self.caller.to_string());
+
+        let mut guard = self.home.schedule.lock().await;
+        let now = (*guard).now();
+        let pause = (*guard).pause(by, now, minutes)?;
+        self.home.events.notify(ChangeReason::Pause);
+        let until = pause.until.map_or_else(|| String::from("resumed"), |until| until.to_string());
+        self.audit.record(self.entry(now, AuditEvent::Pause).with_detail(format!("by {} until {until}", pause.by)));
+        Ok(pause)
+    }
+
+    /// Resumes now, or after `after_minutes`, returning the pause that's left, if any.
+    pub async fn resume(&self, after_minutes: Option<u32>) -> anyhow::Result<Option<Pause>> {
+        let mut guard = self.home.schedule.lock().await;
+        let now = (*guard).now();
+        let pause = (*guard).resume(now, after_minutes)?;
+        self.home.events.notify(ChangeReason::Pause);
+        let entry = self.entry(now, AuditEvent::Resume);
+        self.audit.record(match pause.as_ref().and_then(|p| p.until) {
+            Some(until) => entry.with_detail(format!("at {until}")),
+            None => entry,
+        });
+        Ok(pause)
+    }
+}
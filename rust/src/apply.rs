@@ -0,0 +1,599 @@
+use std::{env, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use chrono::DateTime;
+use chrono_tz::Tz;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    futures::future::join_all,
+    tokio::{
+        self, select,
+        sync::mpsc,
+        time::{sleep, sleep_until, timeout, Instant},
+    },
+    Orbit, Rocket,
+};
+
+use crate::{
+    audit::{AuditEntry, AuditEvent, AuditLog, Caller},
+    events::ChangeReason,
+    homes::{Home, Homes},
+    hue::HueClient,
+    metrics::Metrics,
+    mqtt::MqttSink,
+    overrides::{Expiry, OverrideRequest, OverrideSource},
+    schedule::ChangeAction,
+    sink::{HueSink, LightSink, LogSink, ManualChangeDetector, Observation, SinkKind, SinkStatuses, SinkTarget, WebhookSink},
+};
+
+const DEFAULT_INTERVAL_SECONDS: u64 = 60;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// A sink that takes longer than this to apply an action is counted as failed, so it can't hold up the others.
+const SINK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the lights are left alone after someone changes them with the Hue app or a switch.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ManualHold {
+    Disabled,
+    UntilNextItem,
+    ForHours(u32),
+    UntilLightsCycled,
+}
+
+impl ManualHold {
+    fn expiry(&self) -> Option<Expiry> {
+        match *self {
+            ManualHold::Disabled => None,
+            ManualHold::UntilNextItem => Some(Expiry::UntilNextItem),
+            ManualHold::ForHours(hours) => Some(Expiry::ForMinutes(hours.saturating_mul(60))),
+            ManualHold::UntilLightsCycled => Some(Expiry::UntilLightsCycled),
+        }
+    }
+}
+
+impl FromStr for ManualHold {
+    type Err = anyhow::Error;
+
+    /// One of `disabled`, `next_item`, `off_on`, or a number of hours such as `2h`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(ManualHold::Disabled),
+            "next_item" => Ok(ManualHold::UntilNextItem),
+            "off_on" => Ok(ManualHold::UntilLightsCycled),
+            _ => s
+                .strip_suffix('h')
+                .and_then(|hours| hours.parse().ok())
+                .map(ManualHold::ForHours)
+                .context(format!("Expected disabled, next_item, off_on or a number of hours like 2h, not {s}.")),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ApplyConfig {
+    enabled: bool,
+    interval: Duration,
+    group_name: String,
+    manual_hold: ManualHold,
+    sinks: Vec<SinkKind>,
+}
+
+impl ApplyConfig {
+    /// Setting `env_mqtt_url_var` starts the loop with the mqtt sink even when `env_enabled_var` isn't set, as MQTT
+    /// publishing used to run on its own. `env_group_var` is only required for the hue sink.
+    fn from_env(
+        env_enabled_var: &str,
+        env_interval_var: &str,
+        env_group_var: &str,
+        env_manual_hold_var: &str,
+        env_sinks_var: &str,
+        env_mqtt_url_var: &str,
+    ) -> anyhow::Result<Self> {
+        let enabled = match env::var(env_enabled_var) {
+            Ok(s) => Some(s.parse::<bool>().map_err(|e| anyhow::anyhow!("Invalid {env_enabled_var} ({s}): {e}"))?),
+            Err(_) => None,
+        };
+        let mqtt_configured = env::var(env_mqtt_url_var).is_ok();
+        let interval_seconds = match env::var(env_interval_var) {
+            Ok(s) => s.parse::<u64>().unwrap_or_else(|e| {
+                warn!("Invalid {env_interval_var} ({s}): {e}. Using {DEFAULT_INTERVAL_SECONDS}s.");
+                DEFAULT_INTERVAL_SECONDS
+            }),
+            Err(_) => DEFAULT_INTERVAL_SECONDS,
+        };
+        let manual_hold = match env::var(env_manual_hold_var) {
+            Ok(s) => s.parse().context(format!("Invalid {env_manual_hold_var}"))?,
+            Err(_) => ManualHold::UntilNextItem,
+        };
+        let sinks = match env::var(env_sinks_var) {
+            Ok(s) => s
+                .split(',')
+                .map(|kind| kind.trim().parse())
+                .collect::<anyhow::Result<Vec<SinkKind>>>()
+                .context(format!("Invalid {env_sinks_var}"))?,
+            Err(_) => default_sinks(enabled, mqtt_configured),
+        };
+        let enabled = enabled.unwrap_or(mqtt_configured);
+        let group_name = match (enabled && sinks.contains(&SinkKind::Hue), env::var(env_group_var)) {
+            (_, Ok(name)) => name,
+            (false, Err(_)) => String::new(),
+            (true, Err(_)) => return Err(anyhow::anyhow!("{env_group_var} is required for the hue sink.")),
+        };
+
+        Ok(ApplyConfig { enabled, interval: Duration::from_secs(interval_seconds.max(1)), group_name, manual_hold, sinks })
+    }
+}
+
+/// Doubles the wait after every consecutive failure, up to `MAX_BACKOFF`.
+#[derive(Debug, Default)]
+struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    fn next_delay(&mut self) -> Duration {
+        let delay = INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(self.failures)).min(MAX_BACKOFF);
+        self.failures = self.failures.saturating_add(1);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// A sink, along with what it was last sent and when to try it again after a failure.
+struct SinkRunner {
+    sink: Box<dyn LightSink>,
+    status_index: usize,
+    last_applied: Option<(ChangeAction, SinkTarget)>,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+}
+
+impl SinkRunner {
+    fn new(sink: Box<dyn LightSink>, statuses: &SinkStatuses) -> Self {
+        let status_index = statuses.register(sink.kind());
+        SinkRunner { sink, status_index, last_applied: None, backoff: Backoff::default(), retry_at: None }
+    }
+
+    /// Regular ticks wait out the backoff and skip what was already sent, but a forced update is always sent.
+    fn is_due(&self, change_action: &ChangeAction, target: &SinkTarget, reason: ChangeReason) -> bool {
+        if reason == ChangeReason::Blend && self.retry_at.is_some_and(|at| at > Instant::now()) {
+            return false;
+        }
+        reason == ChangeReason::ForceUpdate || self.last_applied.as_ref().is_none_or(|(a, t)| a != change_action || t != target)
+    }
+
+    /// Returns whether anything was sent.
+    async fn apply(&mut self, change_action: &ChangeAction, target: &SinkTarget) -> anyhow::Result<bool> {
+        let result = match timeout(SINK_TIMEOUT, self.sink.apply(change_action, target)).await {
+            Ok(result) => result,
+            Err(elapsed) => Err(anyhow::Error::new(elapsed).context(format!("Gave up after {SINK_TIMEOUT:?}"))),
+        };
+        match result {
+            Ok(sent) => {
+                self.backoff.reset();
+                self.retry_at = None;
+                self.last_applied = Some((change_action.clone(), target.clone()));
+                Ok(sent)
+            },
+            Err(e) => {
+                self.retry_at = Some(Instant::now() + self.backoff.next_delay());
+                Err(e)
+            },
+        }
+    }
+}
+
+/// Pushes each home's action to its configured sinks, so the Node service isn't needed.
+///
+/// Disabled unless `APPLY_LOOP_ENABLED` is true. `APPLY_SINKS` lists the sinks, and defaults to `hue`. Other homes
+/// read the same settings prefixed with their id, e.g. `CABIN_APPLY_LOOP_ENABLED`.
+pub struct ApplyLoop;
+
+#[rocket::async_trait]
+impl Fairing for ApplyLoop {
+    fn info(&self) -> Info {
+        Info {
+            name: "ApplyLoop",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let (Some(homes), Some(audit), Some(metrics)) = (rocket.state::<Homes>(), rocket.state::<AuditLog>(), rocket.state::<Metrics>()) else {
+            error!("ApplyLoop requires managed Homes, AuditLog and Metrics.");
+            return;
+        };
+        for home in homes.iter() {
+            start(home, audit, metrics);
+        }
+    }
+}
+
+/// Starts `home`'s loop with the sinks from its settings, if it's enabled.
+fn start(home: &Home, audit: &AuditLog, metrics: &Metrics) {
+    let config = ApplyConfig::from_env(
+        &home.env_var("APPLY_LOOP_ENABLED"),
+        &home.env_var("APPLY_INTERVAL_SECONDS"),
+        &home.env_var("HUE_ALL_LIGHTS_GROUP_NAME"),
+        &home.env_var("MANUAL_CHANGE_HOLD"),
+        &home.env_var("APPLY_SINKS"),
+        &home.env_var("MQTT_BROKER_URL"),
+    );
+    let config = match config {
+        Ok(config) if config.enabled => config,
+        Ok(_) => {
+            info!("The apply loop for {home} is disabled.");
+            return;
+        },
+        Err(e) => {
+            error!("Not starting the apply loop for {home}: {e:#}");
+            return;
+        },
+    };
+
+    // A sink that can't be set up is left out, rather than holding back the others.
+    let mut runners = Vec::new();
+    let mut detector = None;
+    for kind in &config.sinks {
+        let sink: anyhow::Result<Box<dyn LightSink>> = match kind {
+            SinkKind::Hue => home.hue
+                .as_ref()
+                .map(|hue| {
+                    let (sink, hue_detector) = HueSink::new(hue.clone());
+                    detector = Some(hue_detector);
+                    Box::new(sink) as Box<dyn LightSink>
+                })
+                .context("No Hue bridge is configured."),
+            SinkKind::Mqtt => MqttSink::from_env(
+                &home.env_var("MQTT_BROKER_URL"),
+                &home.env_var("MQTT_ZIGBEE2MQTT_TOPICS"),
+                &home.env_var("MQTT_STATE_TOPIC"),
+                &home.env_var("MQTT_DISCOVERY_PREFIX"),
+            ).map(|sink| Box::new(sink) as Box<dyn LightSink>),
+            SinkKind::Webhook => WebhookSink::from_env(&home.env_var("WEBHOOK_URL")).map(|sink| Box::new(sink) as Box<dyn LightSink>),
+            SinkKind::Log => Ok(Box::new(LogSink)),
+        };
+        match sink {
+            Ok(sink) => runners.push(SinkRunner::new(sink, &home.statuses)),
+            Err(e) => error!("Not applying {home} to the {kind} sink: {e:#}"),
+        }
+    }
+    if runners.is_empty() {
+        error!("The apply loop for {home} is enabled, but none of its sinks could be set up.");
+        return;
+    }
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    if config.manual_hold == ManualHold::Disabled {
+        detector = None;
+    }
+    if let (Some(hue), Some(detector)) = (&home.hue, detector) {
+        tokio::spawn(watch_bridge_events(hue.clone(), detector, sender));
+    }
+
+    let kinds: Vec<String> = runners.iter().map(|runner| runner.sink.kind().to_string()).collect();
+    match config.group_name.as_str() {
+        "" => info!("Applying the schedule for {home} every {:?} through {}.", config.interval, kinds.join(", ")),
+        group => info!("Applying the schedule for {home} to {group} every {:?} through {}.", config.interval, kinds.join(", ")),
+    }
+    tokio::spawn(run(config, runners, receiver, home.clone(), audit.clone(), metrics.clone()));
+}
+
+/// The sinks used when `APPLY_SINKS` isn't set: hue when the loop was enabled, plus mqtt when a broker is configured.
+fn default_sinks(enabled: Option<bool>, mqtt_configured: bool) -> Vec<SinkKind> {
+    match (enabled, mqtt_configured) {
+        (Some(true), true) => vec![SinkKind::Hue, SinkKind::Mqtt],
+        (_, true) => vec![SinkKind::Mqtt],
+        (_, false) => vec![SinkKind::Hue],
+    }
+}
+
+/// Forwards what the bridge's event stream says about the group, reconnecting whenever it drops.
+async fn watch_bridge_events(hue: HueClient, mut detector: ManualChangeDetector, sender: mpsc::UnboundedSender<Observation>) {
+    let mut backoff = Backoff::default();
+    loop {
+        match hue.events().await {
+            Ok(mut stream) => {
+                backoff.reset();
+                loop {
+                    match stream.next().await {
+                        Ok(Some(updates)) => {
+                            let observations = updates.iter().map(|update| detector.observe(update));
+                            for observation in observations.filter(|o| o != &Observation::Unrelated) {
+                                if sender.send(observation).is_err() {
+                                    return;
+                                }
+                            }
+                        },
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("Bridge event stream failed: {e:#}");
+                            break;
+                        },
+                    }
+                }
+            },
+            Err(e) => warn!("{e:#}"),
+        }
+        sleep(backoff.next_delay()).await;
+    }
+}
+
+async fn run(
+    config: ApplyConfig,
+    mut runners: Vec<SinkRunner>,
+    mut observations: mpsc::UnboundedReceiver<Observation>,
+    home: Home,
+    audit: AuditLog,
+    metrics: Metrics,
+) {
+    let mut subscription = home.events.subscribe_every(config.interval);
+    let mut watching = true;
+
+    loop {
+        let retry_at = runners.iter().filter_map(|runner| runner.retry_at).min();
+        let reason = select! {
+            reason = subscription.wait() => match reason {
+                Some(reason) => reason,
+                None => break,
+            },
+            _ = sleep_until(retry_at.unwrap_or_else(Instant::now)), if retry_at.is_some() => ChangeReason::Blend,
+            observation = observations.recv(), if watching => {
+                match observation {
+                    Some(observation) => handle_observation(observation, &config, &home, &audit).await,
+                    None => watching = false,
+                }
+                continue;
+            },
+        };
+
+        let (now, result, lights) = {
+            let mut guard = home.schedule.lock().await;
+            let now = (*guard).now();
+            (now, (*guard).update_and_get_action(&now), (*guard).light_configs().to_vec())
+        };
+        let change_action = match result {
+            Ok((_, change_action)) => change_action,
+            Err(e) => {
+                error!("Unable to get the action to apply for {home}: {e:#}");
+                metrics.count_error(&home.id, "schedule");
+                continue;
+            },
+        };
+
+        let target = SinkTarget { group: config.group_name.clone(), lights };
+        apply_to_sinks(&mut runners, &change_action, &target, reason, now, &home, &audit).await;
+    }
+}
+
+/// Sends `change_action` to every sink that's due for it, all at once so a slow sink doesn't delay the others.
+/// A failing sink is retried on its own schedule.
+async fn apply_to_sinks(
+    runners: &mut [SinkRunner],
+    change_action: &ChangeAction,
+    target: &SinkTarget,
+    reason: ChangeReason,
+    now: DateTime<Tz>,
+    home: &Home,
+    audit: &AuditLog,
+) {
+    let due = runners.iter_mut().filter(|runner| runner.is_due(change_action, target, reason));
+    let results = join_all(due.map(|runner| async move {
+        let result = runner.apply(change_action, target).await;
+        (runner, result)
+    })).await;
+
+    for (runner, result) in results {
+        let kind = runner.sink.kind();
+        match result {
+            Ok(sent) => {
+                home.statuses.succeeded(runner.status_index, now, change_action, sent);
+                if sent {
+                    info!("Applied {change_action:?} to {target} through {kind} for {home}.");
+                    audit.record(AuditEntry::new(now, AuditEvent::Apply, &Caller::internal("apply loop"))
+                        .with_home(&home.id)
+                        .with_action(change_action.clone())
+                        .with_detail(format!("{target} through {kind}")));
+                }
+            },
+            Err(e) => {
+                warn!("Unable to apply {change_action:?} to {target} through {kind} for {home}: {e:#}");
+                home.statuses.failed(runner.status_index, now, &e);
+            },
+        }
+    }
+}
+
+/// Leaves the lights alone after a manual change by adding an override that does nothing. The override is tagged
+/// as coming from detection, so switching the lights off and on ends it even if it was restored after a restart.
+async fn handle_observation(
+    observation: Observation,
+    config: &ApplyConfig,
+    home: &Home,
+    audit: &AuditLog,
+) {
+    let caller = Caller::internal("manual change detection");
+    match observation {
+        Observation::Unrelated => {},
+        Observation::ManualChange(description) => {
+            let Some(expiry) = config.manual_hold.expiry() else { return };
+            let request = OverrideRequest { change_action: ChangeAction::None, expiry, fade_minutes: Some(0) };
+
+            let mut guard = home.schedule.lock().await;
+            let now = (*guard).now();
+            match (*guard).hold_for_manual_change(&request, now) {
+                Ok((held, true)) => {
+                    info!("Leaving {} alone until {} since the lights were changed manually ({description}).", config.group_name, held.expires);
+                    home.events.notify(ChangeReason::Override);
+                    audit.record(AuditEntry::new(now, AuditEvent::Override, &caller)
+                        .with_home(&home.id)
+                        .with_action(ChangeAction::None)
+                        .with_detail(format!("override {} until {} after {description}", held.id, held.expires)));
+                },
+                Ok((held, false)) => debug!("Still leaving {} alone until {} ({description}).", config.group_name, held.expires),
+                Err(e) => warn!("Unable to hold off {home} after a manual change: {e:#}"),
+            }
+        },
+        Observation::Cycled => {
+            if config.manual_hold != ManualHold::UntilLightsCycled {
+                return;
+            }
+
+            let mut guard = home.schedule.lock().await;
+            let now = (*guard).now();
+            let ids = (*guard).cancel_overrides_from(OverrideSource::ManualChange);
+            if !ids.is_empty() {
+                info!("Resuming {} since the lights were switched off and on.", config.group_name);
+                home.events.notify(ChangeReason::Override);
+                let ids: Vec<String> = ids.iter().map(u64::to_string).collect();
+                audit.record(AuditEntry::new(now, AuditEvent::CancelOverride, &caller)
+                    .with_home(&home.id)
+                    .with_detail(format!("override {}", ids.join(", "))));
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chrono::TimeZone;
+    use chrono_tz::US::Eastern;
+    use crate::{
+        audit::AuditLog,
+        events::{ChangeReason, ScheduleEvents},
+        homes::{Home, DEFAULT_HOME},
+        mock_bridge::MockBridge,
+        schedule::{ChangeAction, Schedule},
+        sink::{HueSink, LightSink, LogSink, Observation, SinkKind, SinkStatuses, SinkTarget},
+    };
+    use super::{apply_to_sinks, default_sinks, handle_observation, ApplyConfig, Backoff, ManualHold, SinkRunner, MAX_BACKOFF};
+
+    struct FailingSink;
+
+    #[rocket::async_trait]
+    impl LightSink for FailingSink {
+        fn kind(&self) -> SinkKind {
+            SinkKind::Webhook
+        }
+
+        async fn apply(&mut self, _change_action: &ChangeAction, _target: &SinkTarget) -> anyhow::Result<bool> {
+            Err(anyhow::anyhow!("unreachable"))
+        }
+    }
+
+    fn living_room() -> SinkTarget {
+        SinkTarget { group: String::from("Living room"), lights: Vec::new() }
+    }
+
+    const YAML: &str = "
+location: { longitude: -74.0, latitude: 40.7, timezone: US/Eastern }
+schedule:
+  - { hour: 6, change: { action: color, mirek: 250, brightness: 100 } }
+";
+
+    fn test_home() -> Home {
+        Home::new(DEFAULT_HOME, Schedule::from_yaml(YAML).unwrap(), ScheduleEvents::from_env("TEST_UNSET_STREAM_POLL_SECONDS"), None)
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+        for _ in 0..40 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), MAX_BACKOFF);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_parse_manual_hold() {
+        assert_eq!("next_item".parse::<ManualHold>().unwrap(), ManualHold::UntilNextItem);
+        assert_eq!("off_on".parse::<ManualHold>().unwrap(), ManualHold::UntilLightsCycled);
+        assert_eq!("3h".parse::<ManualHold>().unwrap(), ManualHold::ForHours(3));
+        assert!("3".parse::<ManualHold>().is_err());
+    }
+
+    #[test]
+    fn test_default_sinks() {
+        assert_eq!(default_sinks(None, false), vec![SinkKind::Hue]);
+        assert_eq!(default_sinks(Some(true), false), vec![SinkKind::Hue]);
+        // A broker on its own publishes without needing a bridge.
+        assert_eq!(default_sinks(None, true), vec![SinkKind::Mqtt]);
+        assert_eq!(default_sinks(Some(true), true), vec![SinkKind::Hue, SinkKind::Mqtt]);
+    }
+
+    #[rocket::async_test]
+    async fn test_runner_skips_unchanged() {
+        let bridge = MockBridge::start().await;
+        bridge.respond_living_room();
+
+        let mut runner = SinkRunner::new(Box::new(HueSink::new(bridge.client()).0), &SinkStatuses::default());
+        let color = ChangeAction::Color { mirek: 300, brightness: 40 };
+        let target = living_room();
+        assert!(runner.is_due(&color, &target, ChangeReason::Blend));
+        assert!(runner.apply(&color, &target).await.unwrap());
+        assert!(!runner.is_due(&color, &target, ChangeReason::Blend));
+        assert!(!runner.is_due(&color, &target, ChangeReason::Override));
+        assert!(runner.is_due(&color, &target, ChangeReason::ForceUpdate));
+        assert!(runner.apply(&color, &target).await.unwrap());
+        assert!(runner.is_due(&ChangeAction::None, &target, ChangeReason::Blend));
+        assert!(!runner.apply(&ChangeAction::None, &target).await.unwrap());
+
+        let puts = bridge.requests().iter().filter(|r| r.method == "PUT").count();
+        assert_eq!(puts, 2);
+        // The group is only looked up once.
+        assert_eq!(bridge.requests().len(), 5);
+    }
+
+    #[rocket::async_test]
+    async fn test_failing_sink_does_not_hold_back_others() {
+        let home = test_home();
+        let statuses = &home.statuses;
+        let mut runners = vec![SinkRunner::new(Box::new(FailingSink), statuses), SinkRunner::new(Box::new(LogSink), statuses)];
+        let color = ChangeAction::Color { mirek: 300, brightness: 40 };
+        let now = Eastern.with_ymd_and_hms(1999, 1, 1, 10, 0, 0).unwrap();
+
+        apply_to_sinks(&mut runners, &color, &living_room(), ChangeReason::Blend, now, &home, &AuditLog::in_memory()).await;
+
+        let all = statuses.all();
+        assert_eq!(all[0].consecutive_failures, 1);
+        assert_eq!(all[0].last_error.as_deref(), Some("unreachable"));
+        assert_eq!(all[1].last_action, Some(color.clone()));
+        // The failed sink waits out its backoff on regular ticks, while the other is up to date.
+        assert!(runners[0].retry_at.is_some());
+        assert!(!runners[0].is_due(&color, &living_room(), ChangeReason::Blend));
+        assert!(!runners[1].is_due(&color, &living_room(), ChangeReason::Blend));
+    }
+
+    #[rocket::async_test]
+    async fn test_cycling_ends_every_manual_hold() {
+        let home = test_home();
+        let config = ApplyConfig {
+            enabled: true,
+            interval: Duration::from_secs(60),
+            group_name: String::from("Living room"),
+            manual_hold: ManualHold::UntilLightsCycled,
+            sinks: vec![SinkKind::Hue],
+        };
+        let audit = AuditLog::in_memory();
+
+        // One event batch can report the same change for every light in the group.
+        for light in ["Lamp", "Bulb", "Strip"] {
+            let observation = Observation::ManualChange(format!("{light} was dimmed"));
+            handle_observation(observation, &config, &home, &audit).await;
+        }
+        assert_eq!(home.schedule.lock().await.overrides().len(), 1);
+
+        handle_observation(Observation::Cycled, &config, &home, &audit).await;
+        assert!(home.schedule.lock().await.overrides().is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env, fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset};
+use chrono_tz::Tz;
+use rocket::{
+    request::{FromRequest, Outcome},
+    serde::{self, json::serde_json},
+    Request,
+};
+
+use crate::{homes::DEFAULT_HOME, schedule::ChangeAction};
+
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+const DEFAULT_FILES: usize = 5;
+/// How many entries are kept when there's no data dir to write them to.
+const IN_MEMORY_LIMIT: usize = 1000;
+const AUDIT_FILE_NAME: &str = "audit.jsonl";
+
+#[derive(Debug, PartialEq, Clone, Copy, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Now,
+    Override,
+    CancelOverride,
+    Pause,
+    Resume,
+    Reload,
+    ForceUpdate,
+    /// A light or group was set directly through `/bridge`.
+    BridgeUpdate,
+    /// The apply loop sent the schedule's action to the bridge.
+    Apply,
+    /// A bridge handed out an API key through `/bridge/pair`.
+    Pair,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct AuditEntry {
+    pub at: DateTime<FixedOffset>,
+    pub event: AuditEvent,
+    pub caller: String,
+    /// Which home this was about, unless it was the default one or every home.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_action: Option<ChangeAction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(at: DateTime<Tz>, event: AuditEvent, caller: &Caller) -> Self {
+        AuditEntry { at: at.fixed_offset(), event, caller: caller.to_string(), home: None, change_action: None, detail: None }
+    }
+
+    /// Names the home, unless it's the default one.
+    pub fn with_home(mut self, home: &str) -> Self {
+        self.home = (home != DEFAULT_HOME).then(|| home.to_string());
+        self
+    }
+
+    pub fn with_action(mut self, change_action: ChangeAction) -> Self {
+        self.change_action = Some(change_action);
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Who asked for a change: the client's IP for HTTP requests, or the name of an internal task.
+#[derive(Debug, Clone)]
+pub struct Caller(String);
+
+impl Caller {
+    pub fn internal(name: &str) -> Self {
+        Caller(name.to_string())
+    }
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Caller {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let caller = request.client_ip().map_or_else(|| String::from("unknown"), |ip: IpAddr| ip.to_string());
+        Outcome::Success(Caller(caller))
+    }
+}
+
+#[derive(Debug)]
+enum Storage {
+    /// Newest entries are appended to `path`. When it grows past `max_bytes`, it's shifted to
+    /// `path.1`, `path.1` to `path.2` and so on, dropping anything past `files`.
+    Files { path: PathBuf, max_bytes: u64, files: usize },
+    Memory(VecDeque<AuditEntry>),
+}
+
+#[derive(Debug)]
+struct Inner {
+    storage: Storage,
+    /// The last `/now` result that was recorded for each home, so polling clients don't flood the log.
+    last_now: HashMap<String, (ChangeAction, Option<String>)>,
+}
+
+/// A queryable record of every change applied to the lights, and why.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl AuditLog {
+    pub fn from_env(env_data_dir_var: &str, env_max_bytes_var: &str, env_files_var: &str) -> Self {
+        let max_bytes = parse_env(env_max_bytes_var, DEFAULT_MAX_BYTES);
+        let files = parse_env(env_files_var, DEFAULT_FILES);
+
+        match env::var(env_data_dir_var) {
+            Ok(dir) => Self::files(PathBuf::from(dir).join(AUDIT_FILE_NAME), max_bytes, files),
+            Err(_) => {
+                info!("{env_data_dir_var} is not set, so only the last {IN_MEMORY_LIMIT} audit entries are kept.");
+                Self::in_memory()
+            },
+        }
+    }
+
+    fn files(path: PathBuf, max_bytes: u64, files: usize) -> Self {
+        Self::with_storage(Storage::Files { path, max_bytes: max_bytes.max(1), files })
+    }
+
+    pub fn in_memory() -> Self {
+        Self::with_storage(Storage::Memory(VecDeque::new()))
+    }
+
+    fn with_storage(storage: Storage) -> Self {
+        AuditLog { inner: Arc::new(Mutex::new(Inner { storage, last_now: HashMap::new() })) }
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Err(e) = inner.storage.append(&entry) {
+            warn!("Unable to write audit entry: {e:#}");
+        }
+    }
+
+    /// Records a home's `/now` result, unless it's the same as that home's previous one.
+    pub fn record_now(&self, entry: AuditEntry) {
+        let mut inner = self.inner.lock().unwrap();
+        let home = entry.home.clone().unwrap_or_else(|| DEFAULT_HOME.to_string());
+        let result = (entry.change_action.clone().unwrap_or(ChangeAction::None), entry.detail.clone());
+        if inner.last_now.get(&home) == Some(&result) {
+            return;
+        }
+        inner.last_now.insert(home, result);
+        if let Err(e) = inner.storage.append(&entry) {
+            warn!("Unable to write audit entry: {e:#}");
+        }
+    }
+
+    /// Entries between `from` and `to` (both inclusive), oldest first.
+    pub fn query(&self, from: Option<DateTime<FixedOffset>>, to: Option<DateTime<FixedOffset>>) -> anyhow::Result<Vec<AuditEntry>> {
+        let in_range = |entry: &AuditEntry| from.is_none_or(|from| entry.at >= from) && to.is_none_or(|to| entry.at <= to);
+
+        let inner = self.inner.lock().unwrap();
+        match &inner.storage {
+            Storage::Memory(entries) => Ok(entries.iter().filter(|e| in_range(e)).cloned().collect()),
+            Storage::Files { path, files, .. } => {
+                let mut entries = Vec::new();
+                for n in (0..=*files).rev() {
+                    let file = rotated_path(path, n);
+                    let jsonl = match fs::read_to_string(&file) {
+                        Ok(s) => s,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                        Err(e) => return Err(e).context(format!("Unable to read {}", file.display())),
+                    };
+                    // A line can only be broken by a crash mid-write, so skip it rather than failing the whole query.
+                    entries.extend(jsonl
+                        .lines()
+                        .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+                        .filter(|e| in_range(e)));
+                }
+                Ok(entries)
+            },
+        }
+    }
+}
+
+impl Storage {
+    fn append(&mut self, entry: &AuditEntry) -> anyhow::Result<()> {
+        match self {
+            Storage::Memory(entries) => {
+                if entries.len() >= IN_MEMORY_LIMIT {
+                    entries.pop_front();
+                }
+                entries.push_back(entry.clone());
+                Ok(())
+            },
+            Storage::Files { path, max_bytes, files } => {
+                let line = serde_json::to_string(entry)? + "\n";
+                let size = fs::metadata(&path).map_or(0, |m| m.len());
+                if size > 0 && size + line.len() as u64 > *max_bytes {
+                    rotate(path, *files)?;
+                }
+
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).context(format!("Unable to create {}", dir.display()))?;
+                }
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .context(format!("Unable to open {}", path.display()))?;
+                file.write_all(line.as_bytes()).context(format!("Unable to write {}", path.display()))?;
+                Ok(())
+            },
+        }
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{n}"));
+    PathBuf::from(rotated)
+}
+
+fn rotate(path: &Path, files: usize) -> anyhow::Result<()> {
+    if files == 0 {
+        return fs::remove_file(path).context(format!("Unable to remove {}", path.display()));
+    }
+    for n in (0..files).rev() {
+        let from = rotated_path(path, n);
+        if from.exists() {
+            let to = rotated_path(path, n + 1);
+            fs::rename(&from, &to).context(format!("Unable to rotate {} to {}", from.display(), to.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_env<T: std::str::FromStr + fmt::Display>(var: &str, default: T) -> T
+where
+    T::Err: fmt::Display,
+{
+    match env::var(var) {
+        Ok(s) => s.parse::<T>().unwrap_or_else(|e| {
+            warn!("Invalid {var} ({s}): {e}. Using {default}.");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use chrono::{TimeDelta, TimeZone};
+    use chrono_tz::US::Eastern;
+    use crate::{homes::DEFAULT_HOME, schedule::ChangeAction};
+    use super::{rotated_path, AuditEntry, AuditEvent, AuditLog, Caller, AUDIT_FILE_NAME};
+
+    #[test]
+    fn test_now_results_are_deduplicated() {
+        let log = AuditLog::in_memory();
+        let start = Eastern.with_ymd_and_hms(1999, 1, 1, 10, 0, 0).unwrap();
+        let caller = Caller::internal("test");
+        let color = ChangeAction::Color { mirek: 300, brightness: 50 };
+
+        for minute in 0..3 {
+            log.record_now(AuditEntry::new(start + TimeDelta::minutes(minute), AuditEvent::Now, &caller).with_action(color.clone()));
+        }
+        log.record(AuditEntry::new(start + TimeDelta::minutes(3), AuditEvent::Pause, &caller));
+        log.record_now(AuditEntry::new(start + TimeDelta::minutes(4), AuditEvent::Now, &caller).with_action(ChangeAction::None).with_detail("paused"));
+
+        let events: Vec<AuditEvent> = log.query(None, None).unwrap().iter().map(|e| e.event).collect();
+        assert_eq!(events, vec![AuditEvent::Now, AuditEvent::Pause, AuditEvent::Now]);
+
+        // Homes polled in turn only record their own changes.
+        for minute in 5..7 {
+            let at = start + TimeDelta::minutes(minute);
+            log.record_now(AuditEntry::new(at, AuditEvent::Now, &caller).with_action(ChangeAction::None).with_detail("paused"));
+            log.record_now(AuditEntry::new(at, AuditEvent::Now, &caller).with_home("cabin").with_action(color.clone()));
+        }
+        let entries = log.query(None, None).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3].home.as_deref(), Some("cabin"));
+        assert_eq!(AuditEntry::new(start, AuditEvent::Now, &caller).with_home(DEFAULT_HOME).home, None);
+
+        let from = (start + TimeDelta::minutes(3)).fixed_offset();
+        let to = (start + TimeDelta::minutes(3)).fixed_offset();
+        let events: Vec<AuditEvent> = log.query(Some(from), Some(to)).unwrap().iter().map(|e| e.event).collect();
+        assert_eq!(events, vec![AuditEvent::Pause]);
+    }
+
+    #[test]
+    fn test_files_rotate() {
+        let dir = env::temp_dir().join(format!("rust-hue-audit-test-{}", std::process::id()));
+        let path = dir.join(AUDIT_FILE_NAME);
+        // Small enough that every entry rotates the previous one out.
+        let log = AuditLog::files(path.clone(), 10, 2);
+        let start = Eastern.with_ymd_and_hms(1999, 1, 1, 10, 0, 0).unwrap();
+
+        for minute in 0..4 {
+            log.record(AuditEntry::new(start + TimeDelta::minutes(minute), AuditEvent::ForceUpdate, &Caller::internal("test")));
+        }
+
+        let entries = log.query(None, None).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].at, (start + TimeDelta::minutes(1)).fixed_offset());
+        assert!(!rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
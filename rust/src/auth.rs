@@ -0,0 +1,374 @@
+//! Bearer tokens for the API. Each token has a scope, and each scope includes the ones before it,
+//! so an `admin` token can do anything an `override` or `read` token can.
+
+use std::{env, fmt, fs, marker::PhantomData, str::FromStr};
+
+use anyhow::Context;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{uri::Origin, Status},
+    request::{FromRequest, Outcome},
+    Data, Request,
+};
+use sha2::{Digest, Sha256};
+
+const AUTHORIZATION_HEADER: &str = "Authorization";
+const ACCESS_TOKEN_PARAM: &str = "access_token";
+const WEBSOCKET_PROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+/// Offered alongside a token as WebSocket subprotocols, and echoed back, since browsers drop the connection otherwise.
+pub const BEARER_PROTOCOL: &str = "bearer";
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Scope {
+    /// Reading the schedule, its state and the bridge's lights.
+    Read,
+    /// Overrides, pauses and forced updates.
+    Override,
+    /// Replacing the schedule, setting lights directly and pairing.
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "override" => Ok(Scope::Override),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(anyhow::anyhow!("Expected read, override or admin, not {s}.")),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Override => write!(f, "override"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Token {
+    name: String,
+    scope: Scope,
+    /// Only the hash is kept, so comparing against it doesn't leak the token through timing.
+    sha256: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    Forbidden { name: String, scope: Scope, required: Scope },
+    /// No tokens are configured, and the API wasn't explicitly left open.
+    Unconfigured,
+}
+
+impl AuthError {
+    fn status(&self) -> Status {
+        match self {
+            AuthError::Missing | AuthError::Invalid => Status::Unauthorized,
+            AuthError::Forbidden { .. } | AuthError::Unconfigured => Status::Forbidden,
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "An Authorization: Bearer <token> header is required."),
+            AuthError::Invalid => write!(f, "The bearer token isn't valid."),
+            AuthError::Forbidden { name, scope, required } =>
+                write!(f, "Token {name} has the {scope} scope, but {required} is required."),
+            AuthError::Unconfigured => write!(f, "No API tokens are configured, so this route can't be used."),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiTokens {
+    tokens: Vec<Token>,
+    /// Whether routes that only need `read` can be used without a token.
+    anonymous_read: bool,
+    /// Whether every route can be used without a token.
+    disabled: bool,
+}
+
+impl ApiTokens {
+    /// Tokens are `name:scope:token` entries, separated by commas or newlines, from `env_tokens_var`
+    /// and the file at `env_tokens_file_var` (e.g. a mounted secret). Lines starting with `#` are skipped.
+    /// Without any tokens, only open routes (and reads, with `env_anonymous_read_var`) can be used,
+    /// unless `env_disabled_var` is true, which opens every route.
+    pub fn from_env(
+        env_tokens_var: &str,
+        env_tokens_file_var: &str,
+        env_anonymous_read_var: &str,
+        env_disabled_var: &str,
+    ) -> anyhow::Result<Self> {
+        let mut tokens = match env::var(env_tokens_var) {
+            Ok(s) => parse_tokens(&s).context(format!("Invalid {env_tokens_var}"))?,
+            Err(_) => vec![],
+        };
+        if let Ok(path) = env::var(env_tokens_file_var) {
+            let s = fs::read_to_string(&path).context(format!("Unable to read {env_tokens_file_var} {path}"))?;
+            tokens.extend(parse_tokens(&s).context(format!("Invalid {env_tokens_file_var} {path}"))?);
+        }
+        let anonymous_read = flag(env_anonymous_read_var, "reads need a token");
+        let disabled = flag(env_disabled_var, "tokens are required");
+
+        if disabled {
+            warn!("{env_disabled_var} is set, so anyone who can reach the API can use all of it.");
+        } else if tokens.is_empty() {
+            let usable = if anonymous_read { "open routes and reads" } else { "open routes" };
+            warn!("No API tokens are configured, so only {usable} can be used. Set {env_tokens_var} or {env_tokens_file_var}, \
+                or {env_disabled_var}=true to open every route.");
+        }
+        Ok(ApiTokens { tokens, anonymous_read, disabled })
+    }
+
+    /// Checks an `Authorization` header against the scope a route needs.
+    fn authorize(&self, authorization: Option<&str>, required: Scope) -> Result<(), AuthError> {
+        if self.disabled {
+            return Ok(());
+        }
+        let Some(authorization) = authorization else {
+            return match (required == Scope::Read && self.anonymous_read, self.tokens.is_empty()) {
+                (true, _) => Ok(()),
+                (false, true) => Err(AuthError::Unconfigured),
+                (false, false) => Err(AuthError::Missing),
+            };
+        };
+
+        let bearer = authorization.split_once(' ')
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim())
+            .ok_or(AuthError::Invalid)?;
+        let sha256 = Sha256::digest(bearer.as_bytes());
+        let token = self.tokens.iter().find(|t| t.sha256 == sha256.as_slice()).ok_or(AuthError::Invalid)?;
+        if token.scope < required {
+            return Err(AuthError::Forbidden { name: token.name.clone(), scope: token.scope, required });
+        }
+        Ok(())
+    }
+}
+
+/// Whether `env_var` is true, falling back to false when it's unset or invalid.
+fn flag(env_var: &str, fallback: &str) -> bool {
+    match env::var(env_var).map(|s| s.parse()) {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            warn!("Invalid {env_var}, so {fallback}: {e}");
+            false
+        },
+        Err(_) => false,
+    }
+}
+
+fn parse_tokens(s: &str) -> anyhow::Result<Vec<Token>> {
+    s.split([',', '\n'])
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty() && !entry.starts_with('#'))
+        .map(|entry| {
+            let mut parts = entry.splitn(3, ':');
+            let (Some(name), Some(scope), Some(token)) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(anyhow::anyhow!("Expected name:scope:token, not an entry starting with {:?}.", entry.split(':').next().unwrap_or_default()));
+            };
+            if token.is_empty() {
+                return Err(anyhow::anyhow!("Token {name} is empty."));
+            }
+            Ok(Token {
+                name: name.to_string(),
+                scope: scope.parse().context(format!("Invalid scope for token {name}"))?,
+                sha256: Sha256::digest(token.as_bytes()).to_vec(),
+            })
+        })
+        .collect()
+}
+
+pub trait RequiredScope: Send + Sync + 'static {
+    const SCOPE: Scope;
+}
+
+pub struct ReadScope;
+pub struct OverrideScope;
+pub struct AdminScope;
+
+impl RequiredScope for ReadScope {
+    const SCOPE: Scope = Scope::Read;
+}
+
+impl RequiredScope for OverrideScope {
+    const SCOPE: Scope = Scope::Override;
+}
+
+impl RequiredScope for AdminScope {
+    const SCOPE: Scope = Scope::Admin;
+}
+
+/// A request guard that only lets requests through with a token for scope `S`, or that fails
+/// with 401 or 403 for the catchers to answer.
+pub struct Authorized<S>(PhantomData<S>);
+
+pub type ReadAccess = Authorized<ReadScope>;
+pub type OverrideAccess = Authorized<OverrideScope>;
+pub type AdminAccess = Authorized<AdminScope>;
+
+/// Why the request was turned away, kept in the request's local cache for the catchers.
+struct AuthFailure(Option<String>);
+
+/// The message for a 401 or 403 response.
+pub fn failure_message(request: &Request<'_>, status: Status) -> String {
+    match &request.local_cache(|| AuthFailure(None)).0 {
+        Some(message) => message.clone(),
+        None => status.reason_lossy().to_string(),
+    }
+}
+
+fn guard(request: &Request<'_>, authorization: Option<&str>, required: Scope) -> Outcome<(), AuthError> {
+    let Some(tokens) = request.rocket().state::<ApiTokens>() else {
+        return Outcome::Success(());
+    };
+    match tokens.authorize(authorization, required) {
+        Ok(()) => Outcome::Success(()),
+        Err(e) => {
+            request.local_cache(|| AuthFailure(Some(e.to_string())));
+            Outcome::Error((e.status(), e))
+        },
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, S: RequiredScope> FromRequest<'r> for Authorized<S> {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        guard(request, request.headers().get_one(AUTHORIZATION_HEADER), S::SCOPE).map(|()| Authorized(PhantomData))
+    }
+}
+
+/// Like `Authorized`, for `/stream` and `/ws`. Browsers' `EventSource` and `WebSocket` can't set headers, so the token
+/// can also be given as an `access_token` query parameter, or by offering the WebSocket subprotocols `bearer` and the token.
+pub struct StreamAuthorized<S> {
+    /// Whether the token came as a subprotocol, so `bearer` has to be accepted in the response.
+    pub bearer_protocol: bool,
+    scope: PhantomData<S>,
+}
+
+pub type StreamReadAccess = StreamAuthorized<ReadScope>;
+pub type StreamOverrideAccess = StreamAuthorized<OverrideScope>;
+
+/// An `access_token` that `QueryTokens` took out of the URI.
+struct QueryToken(Option<String>);
+
+/// Takes `access_token` out of the URI before Rocket or `AutoLogger` log it, keeping it for `StreamAuthorized`.
+pub struct QueryTokens;
+
+#[rocket::async_trait]
+impl Fairing for QueryTokens {
+    fn info(&self) -> Info {
+        Info { name: "QueryTokens", kind: Kind::Request }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(token) = request.query_value::<String>(ACCESS_TOKEN_PARAM).and_then(Result::ok) else { return };
+        if let Ok(uri) = Origin::parse_owned(without_param(request.uri(), ACCESS_TOKEN_PARAM)) {
+            request.set_uri(uri);
+        }
+        request.local_cache(|| QueryToken(Some(token)));
+    }
+}
+
+fn without_param(uri: &Origin<'_>, name: &str) -> String {
+    let query: Vec<&str> = uri.query()
+        .map(|query| query.as_str().split('&').filter(|pair| pair.split('=').next() != Some(name)).collect())
+        .unwrap_or_default();
+    match query.is_empty() {
+        true => uri.path().to_string(),
+        false => format!("{}?{}", uri.path(), query.join("&")),
+    }
+}
+
+/// The token offered alongside `bearer` in a `Sec-WebSocket-Protocol` header.
+fn protocol_token(protocols: &str) -> Option<&str> {
+    let mut protocols = protocols.split(',').map(str::trim);
+    if !protocols.clone().any(|protocol| protocol == BEARER_PROTOCOL) {
+        return None;
+    }
+    protocols.find(|protocol| *protocol != BEARER_PROTOCOL && !protocol.is_empty())
+}
+
+#[rocket::async_trait]
+impl<'r, S: RequiredScope> FromRequest<'r> for StreamAuthorized<S> {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let header = request.headers().get_one(AUTHORIZATION_HEADER).map(String::from);
+        let query = request.local_cache(|| {
+            QueryToken(request.query_value::<String>(ACCESS_TOKEN_PARAM).and_then(Result::ok))
+        }).0.as_deref();
+        let protocol = request.headers().get_one(WEBSOCKET_PROTOCOL_HEADER).and_then(protocol_token);
+
+        let bearer_protocol = header.is_none() && query.is_none() && protocol.is_some();
+        let authorization = header.or_else(|| query.or(protocol).map(|token| format!("Bearer {token}")));
+        guard(request, authorization.as_deref(), S::SCOPE).map(|()| StreamAuthorized { bearer_protocol, scope: PhantomData })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::uri::Origin;
+    use super::{parse_tokens, protocol_token, without_param, ApiTokens, AuthError, Scope};
+
+    #[test]
+    fn test_authorize() {
+        let tokens = parse_tokens("# Comment\nfrontend:read:abc, ci:admin:x:y\n\nphone:override:def").unwrap();
+        assert_eq!(tokens.len(), 3);
+        let mut api_tokens = ApiTokens { tokens, anonymous_read: false, disabled: false };
+
+        assert_eq!(api_tokens.authorize(None, Scope::Read), Err(AuthError::Missing));
+        assert_eq!(api_tokens.authorize(Some("Bearer nope"), Scope::Read), Err(AuthError::Invalid));
+        assert_eq!(api_tokens.authorize(Some("Basic abc"), Scope::Read), Err(AuthError::Invalid));
+        assert_eq!(api_tokens.authorize(Some("Bearer abc"), Scope::Read), Ok(()));
+        assert_eq!(api_tokens.authorize(Some("bearer x:y"), Scope::Admin), Ok(()));
+        assert_eq!(
+            api_tokens.authorize(Some("Bearer def"), Scope::Admin),
+            Err(AuthError::Forbidden { name: String::from("phone"), scope: Scope::Override, required: Scope::Admin }),
+        );
+        assert_eq!(api_tokens.authorize(Some("Bearer def"), Scope::Read), Ok(()));
+
+        api_tokens.anonymous_read = true;
+        assert_eq!(api_tokens.authorize(None, Scope::Read), Ok(()));
+        assert_eq!(api_tokens.authorize(None, Scope::Override), Err(AuthError::Missing));
+        assert_eq!(api_tokens.authorize(Some("Bearer nope"), Scope::Read), Err(AuthError::Invalid));
+
+        // Without tokens, only what's explicitly opened can be used.
+        api_tokens.tokens.clear();
+        assert_eq!(api_tokens.authorize(None, Scope::Read), Ok(()));
+        assert_eq!(api_tokens.authorize(None, Scope::Admin), Err(AuthError::Unconfigured));
+        api_tokens.disabled = true;
+        assert_eq!(api_tokens.authorize(None, Scope::Admin), Ok(()));
+
+        assert!(parse_tokens("frontend:read").is_err());
+        assert!(parse_tokens("frontend:write:abc").is_err());
+        assert!(parse_tokens("frontend:read:").is_err());
+    }
+
+    #[test]
+    fn test_protocol_token() {
+        assert_eq!(protocol_token("bearer, abc"), Some("abc"));
+        assert_eq!(protocol_token("abc,bearer"), Some("abc"));
+        assert_eq!(protocol_token("abc"), None);
+        assert_eq!(protocol_token("bearer"), None);
+    }
+
+    #[test]
+    fn test_without_param() {
+        let uri = Origin::parse("/stream?access_token=abc&x=1").unwrap();
+        assert_eq!(without_param(&uri, "access_token"), "/stream?x=1");
+        assert_eq!(without_param(&Origin::parse("/ws?access_token=abc").unwrap(), "access_token"), "/ws");
+    }
+}
@@ -0,0 +1,139 @@
+use rocket::serde;
+
+use crate::{
+    hue::{Light, LightUpdate},
+    schedule::{ChangeAction, MAX_MIREK, MIN_MIREK},
+};
+
+/// Declares what a light can do in the schedule YAML, for bulbs the bridge misreports.
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct LightConfig {
+    /// The light's name in the Hue app, or its id.
+    pub name: String,
+    /// `false` for bulbs that can only dim.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_temperature: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirek_min: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirek_max: Option<u16>,
+}
+
+impl LightConfig {
+    fn matches(&self, light: &Light) -> bool {
+        self.name == light.metadata.name || self.name == light.id
+    }
+
+    fn apply(&self, learned: Capabilities) -> Capabilities {
+        if self.color_temperature == Some(false) {
+            return Capabilities { mirek_range: None };
+        }
+
+        let declares_range = self.color_temperature == Some(true) || self.mirek_min.is_some() || self.mirek_max.is_some();
+        let mirek_range = match learned.mirek_range {
+            Some(range) => Some(range),
+            None if declares_range => Some((MIN_MIREK, MAX_MIREK)),
+            None => None,
+        };
+        Capabilities {
+            mirek_range: mirek_range.map(|(min, max)| (self.mirek_min.unwrap_or(min), self.mirek_max.unwrap_or(max))),
+        }
+    }
+
+    pub fn warning(&self) -> Option<String> {
+        match (self.mirek_min, self.mirek_max) {
+            (Some(min), Some(max)) if min > max => Some(format!("Light {} has mirek_min {min} over mirek_max {max}.", self.name)),
+            _ => None,
+        }
+    }
+}
+
+/// What a single bulb supports.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Capabilities {
+    /// The supported mirek range, or `None` for bulbs that can only dim.
+    pub mirek_range: Option<(u16, u16)>,
+}
+
+impl Capabilities {
+    /// What the bridge reports, with anything declared for the light in `configs` taking precedence.
+    pub fn of(light: &Light, configs: &[LightConfig]) -> Self {
+        let learned = Capabilities {
+            mirek_range: light.color_temperature.as_ref().map(|color_temperature| match color_temperature.mirek_schema {
+                Some(schema) => (schema.mirek_minimum, schema.mirek_maximum),
+                None => (MIN_MIREK, MAX_MIREK),
+            }),
+        };
+
+        match configs.iter().find(|config| config.matches(light)) {
+            Some(config) => config.apply(learned),
+            None => learned,
+        }
+    }
+
+    /// The closest this light can get to `change_action`. `None` if the action leaves the lights alone.
+    pub fn update_for(&self, change_action: &ChangeAction) -> Option<LightUpdate> {
+        let mut update = LightUpdate::from_action(change_action)?;
+        update.color_temperature = match (update.color_temperature, self.mirek_range) {
+            (Some(mut color_temperature), Some((min, max))) => {
+                color_temperature.mirek = color_temperature.mirek.map(|mirek| mirek.clamp(min, max.max(min)));
+                Some(color_temperature)
+            },
+            _ => None,
+        };
+        Some(update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::serde::json::{self, json};
+    use crate::{hue::Light, schedule::ChangeAction};
+    use super::{Capabilities, LightConfig};
+
+    fn light(name: &str, color_temperature: rocket::serde::json::Value) -> Light {
+        let mut light = json!({
+            "id": format!("{name}-id"),
+            "owner": { "rid": "device-1", "rtype": "device" },
+            "metadata": { "name": name },
+            "on": { "on": true },
+            "dimming": { "brightness": 100.0 },
+        });
+        if !color_temperature.is_null() {
+            light["color_temperature"] = color_temperature;
+        }
+        json::from_value(light).unwrap()
+    }
+
+    #[test]
+    fn test_capabilities_are_learned_and_declared() {
+        let ambiance = light("Ambiance", json!({ "mirek": 300, "mirek_schema": { "mirek_minimum": 153, "mirek_maximum": 454 } }));
+        let white = light("White", rocket::serde::json::Value::Null);
+
+        assert_eq!(Capabilities::of(&ambiance, &[]), Capabilities { mirek_range: Some((153, 454)) });
+        assert_eq!(Capabilities::of(&white, &[]), Capabilities { mirek_range: None });
+
+        let configs = vec![
+            LightConfig { name: String::from("Ambiance"), color_temperature: None, mirek_min: Some(200), mirek_max: None },
+            LightConfig { name: String::from("White-id"), color_temperature: Some(true), mirek_min: None, mirek_max: None },
+        ];
+        assert_eq!(Capabilities::of(&ambiance, &configs), Capabilities { mirek_range: Some((200, 454)) });
+        assert_eq!(Capabilities::of(&white, &configs), Capabilities { mirek_range: Some((153, 500)) });
+    }
+
+    #[test]
+    fn test_update_is_mapped_to_capabilities() {
+        let warm = ChangeAction::Color { mirek: 500, brightness: 30 };
+
+        let update = Capabilities { mirek_range: Some((153, 454)) }.update_for(&warm).unwrap();
+        assert_eq!(update.color_temperature.unwrap().mirek, Some(454));
+        assert_eq!(update.dimming.unwrap().brightness, 30.0);
+
+        let update = Capabilities { mirek_range: None }.update_for(&warm).unwrap();
+        assert_eq!(update.color_temperature, None);
+        assert_eq!(update.dimming.unwrap().brightness, 30.0);
+
+        assert_eq!(Capabilities { mirek_range: None }.update_for(&ChangeAction::None), None);
+    }
+}
@@ -0,0 +1,271 @@
+use std::{env, sync::Arc, time::Duration};
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    serde,
+    tokio::{
+        self, select,
+        sync::{broadcast::{self, error::RecvError}, Mutex, Notify},
+        time::{interval, Interval},
+    },
+    Orbit, Rocket,
+};
+
+use crate::{homes::Homes, schedule::{ChangeAction, Schedule}};
+
+const DEFAULT_POLL_SECONDS: u64 = 10;
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Why an event was pushed to stream subscribers.
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ChangeReason {
+    /// The blended value drifted to a new value as time passed.
+    Blend,
+    ForceUpdate,
+    Override,
+    Pause,
+    Reload,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub struct StreamEvent {
+    pub reason: ChangeReason,
+    pub now: DateTime<Tz>,
+    pub change_action: ChangeAction,
+    pub just_updated: bool,
+}
+
+/// One poll of the schedule, shared by every stream subscriber.
+#[derive(Debug, Clone)]
+struct Polled {
+    reason: ChangeReason,
+    outcome: Result<StreamEvent, String>,
+}
+
+/// Fan-out point for anything that changes the schedule's output outside of the normal passage of time.
+#[derive(Clone)]
+pub struct ScheduleEvents {
+    sender: broadcast::Sender<ChangeReason>,
+    polled: broadcast::Sender<Polled>,
+    /// Asks the publisher for a poll straight away, so a new stream subscriber doesn't wait for the next tick.
+    refresh: Arc<Notify>,
+    poll_interval: Duration,
+}
+
+impl ScheduleEvents {
+    pub fn from_env(env_poll_var: &str) -> Self {
+        let poll_seconds = match env::var(env_poll_var) {
+            Ok(s) => s.parse::<u64>().unwrap_or_else(|e| {
+                warn!("Invalid {env_poll_var} ({s}): {e}. Using {DEFAULT_POLL_SECONDS}s.");
+                DEFAULT_POLL_SECONDS
+            }),
+            Err(_) => DEFAULT_POLL_SECONDS,
+        };
+        Self::new(Duration::from_secs(poll_seconds.max(1)))
+    }
+
+    pub fn new(poll_interval: Duration) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let (polled, _) = broadcast::channel(CHANNEL_CAPACITY);
+        ScheduleEvents { sender, polled, refresh: Arc::new(Notify::new()), poll_interval }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    pub fn notify(&self, reason: ChangeReason) {
+        // An error only means nobody is currently subscribed.
+        let _ = self.sender.send(reason);
+    }
+
+    /// Wakes on every tick of the configured stream interval, and on every notification.
+    pub fn subscribe(&self) -> Subscription {
+        self.subscribe_every(self.poll_interval)
+    }
+
+    /// Like `subscribe`, but ticks every `poll_interval` instead of the configured stream interval.
+    pub fn subscribe_every(&self, poll_interval: Duration) -> Subscription {
+        Subscription {
+            receiver: self.sender.subscribe(),
+            ticker: interval(poll_interval),
+        }
+    }
+
+    /// A single client's view of the schedule, shared by the SSE and WebSocket endpoints. The schedule is polled
+    /// once for all of them by `publish`.
+    pub fn stream(&self) -> StreamSubscription {
+        let receiver = self.polled.subscribe();
+        self.refresh.notify_one();
+        StreamSubscription { receiver, tracker: ActionTracker::default() }
+    }
+}
+
+/// Polls the schedule on every tick and notification, and sends what it found to every stream subscriber, so the
+/// schedule is locked once per tick however many clients are connected.
+pub async fn publish(events: ScheduleEvents, schedule: Arc<Mutex<Schedule>>) {
+    let mut subscription = events.subscribe();
+    loop {
+        let reason = select! {
+            reason = subscription.wait() => match reason {
+                Some(reason) => reason,
+                None => break,
+            },
+            _ = events.refresh.notified() => ChangeReason::Blend,
+        };
+        if events.polled.receiver_count() == 0 {
+            continue;
+        }
+
+        let outcome = {
+            let mut guard = schedule.lock().await;
+            let now = (*guard).now();
+            (*guard).update_and_get_action(&now)
+                .map(|(just_updated, change_action)| StreamEvent { reason, now, change_action, just_updated })
+                .map_err(|e| e.to_string())
+        };
+        // An error only means everyone unsubscribed in the meantime.
+        let _ = events.polled.send(Polled { reason, outcome });
+    }
+}
+
+/// Starts `publish` for every home.
+pub struct StreamPublisher;
+
+#[rocket::async_trait]
+impl Fairing for StreamPublisher {
+    fn info(&self) -> Info {
+        Info { name: "Stream publisher", kind: Kind::Liftoff }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let Some(homes) = rocket.state::<Homes>() else {
+            error!("StreamPublisher requires managed Homes.");
+            return;
+        };
+        for home in homes.iter() {
+            tokio::spawn(publish(home.events.clone(), home.schedule.clone()));
+        }
+    }
+}
+
+/// Wakes a task that polls the schedule itself, such as the apply loop.
+pub struct Subscription {
+    receiver: broadcast::Receiver<ChangeReason>,
+    ticker: Interval,
+}
+
+impl Subscription {
+    /// Waits for the next poll tick or notification. Returns `None` once the channel closes.
+    ///
+    /// This is cancel safe, so it can be used as a `select!` branch.
+    pub async fn wait(&mut self) -> Option<ChangeReason> {
+        loop {
+            select! {
+                _ = self.ticker.tick() => return Some(ChangeReason::Blend),
+                message = self.receiver.recv() => match message {
+                    Ok(reason) => return Some(reason),
+                    Err(RecvError::Closed) => return None,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+            }
+        }
+    }
+}
+
+pub struct StreamSubscription {
+    receiver: broadcast::Receiver<Polled>,
+    tracker: ActionTracker,
+}
+
+impl StreamSubscription {
+    /// Waits for the next poll this subscriber should be told about. Returns `None` once the channel closes.
+    ///
+    /// This is cancel safe, so it can be used as a `select!` branch.
+    pub async fn next(&mut self) -> Option<Result<StreamEvent, String>> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(polled) => {
+                    let outcome = polled.outcome.as_ref().map(|event| event.change_action.clone()).map_err(String::clone);
+                    if self.tracker.should_emit(polled.reason, &outcome) {
+                        return Some(polled.outcome);
+                    }
+                },
+                Err(RecvError::Closed) => return None,
+                Err(RecvError::Lagged(_)) => continue,
+            }
+        }
+    }
+}
+
+/// Remembers what a subscriber last saw so that periodic polls only emit when something changed.
+#[derive(Debug, Default)]
+struct ActionTracker {
+    last: Option<Result<ChangeAction, String>>,
+}
+
+impl ActionTracker {
+    fn should_emit(&mut self, reason: ChangeReason, outcome: &Result<ChangeAction, String>) -> bool {
+        let changed = self.last.as_ref() != Some(outcome);
+        self.last = Some(outcome.clone());
+        changed || reason != ChangeReason::Blend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use rocket::tokio::{self, sync::Mutex};
+    use crate::schedule::{ChangeAction, Schedule};
+    use super::{publish, ActionTracker, ChangeReason, ScheduleEvents};
+
+    #[test]
+    fn test_tracker_only_emits_blend_on_change() {
+        let mut tracker = ActionTracker::default();
+        let color = Ok(ChangeAction::Color { mirek: 300, brightness: 50 });
+
+        assert!(tracker.should_emit(ChangeReason::Blend, &color));
+        assert!(!tracker.should_emit(ChangeReason::Blend, &color));
+        assert!(tracker.should_emit(ChangeReason::Blend, &Ok(ChangeAction::Color { mirek: 301, brightness: 50 })));
+        assert!(tracker.should_emit(ChangeReason::Blend, &Ok(ChangeAction::None)));
+        assert!(tracker.should_emit(ChangeReason::Blend, &Err(String::from("bad"))));
+        assert!(!tracker.should_emit(ChangeReason::Blend, &Err(String::from("bad"))));
+    }
+
+    #[test]
+    fn test_tracker_always_emits_notifications() {
+        let mut tracker = ActionTracker::default();
+        let none = Ok(ChangeAction::None);
+
+        assert!(tracker.should_emit(ChangeReason::Blend, &none));
+        assert!(tracker.should_emit(ChangeReason::ForceUpdate, &none));
+        assert!(!tracker.should_emit(ChangeReason::Blend, &none));
+    }
+
+    #[rocket::async_test]
+    async fn test_subscribers_share_one_poll() {
+        let yaml = "
+location: { longitude: -74.0, latitude: 40.7, timezone: US/Eastern }
+schedule:
+  - { hour: 6, change: { action: stop } }
+";
+        let schedule = Arc::new(Mutex::new(Schedule::from_yaml(yaml).unwrap()));
+        let events = ScheduleEvents::from_env("TEST_UNSET_STREAM_POLL_SECONDS");
+        let (mut first, mut second) = (events.stream(), events.stream());
+        tokio::spawn(publish(events.clone(), schedule));
+
+        // Subscribing asks for a poll, rather than waiting for the next tick.
+        let (first_event, second_event) = (first.next().await.unwrap().unwrap(), second.next().await.unwrap().unwrap());
+        assert_eq!(first_event.now, second_event.now);
+        assert_eq!(first_event.change_action, ChangeAction::None);
+
+        events.notify(ChangeReason::ForceUpdate);
+        let (first_event, second_event) = (first.next().await.unwrap().unwrap(), second.next().await.unwrap().unwrap());
+        assert_eq!((first_event.reason, second_event.reason), (ChangeReason::ForceUpdate, ChangeReason::ForceUpdate));
+        assert_eq!(first_event.now, second_event.now);
+    }
+}
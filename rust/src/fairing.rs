@@ -1,8 +1,183 @@
+use std::{convert::Infallible, env, fmt, str::FromStr, sync::OnceLock, time::Instant};
+
 use rocket::{Request, Data, Response};
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::Status;
+use rocket::http::{Status, StatusClass};
+use rocket::request::{self, FromRequest};
+use rocket::serde::json::{serde_json, Value};
+use uuid::Uuid;
+
+use crate::schedule::ChangeAction;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Longer IDs, or ones with spaces or control characters, are replaced rather than written to the logs.
+const MAX_REQUEST_ID_LEN: usize = 128;
+const DEFAULT_BODY_MAX_BYTES: usize = 2048;
+const DEFAULT_REDACTED_FIELDS: &str = "api_key,client_key,password,secret,token";
+const REDACTED: &str = "[redacted]";
+/// Requests that didn't match a route are grouped together, so scanners can't blow up label counts.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// When the request arrived, kept in the request's local cache.
+pub struct RequestStart(pub Instant);
+
+/// Taken from the request's `X-Request-Id` if it has a usable one, and sent back in the response's.
+#[derive(Debug, Clone)]
+pub struct RequestId(String);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
-pub struct AutoLogger;
+/// The request's ID, as set by `AutoLogger`, or a new one if it isn't attached.
+pub fn request_id<'r>(request: &'r Request<'_>) -> &'r RequestId {
+    request.local_cache(|| RequestId(Uuid::new_v4().to_string()))
+}
+
+/// What a handler worked out for a home, for `AutoLogger` to include in the response's log line.
+struct LoggedAction {
+    home: String,
+    segment: Option<usize>,
+    change_action: ChangeAction,
+}
+
+/// Hands `AutoLogger` the action a handler worked out, through the request's local cache,
+/// so the logger never has to look at, or update, the schedule itself.
+pub struct ActionLog<'r>(&'r OnceLock<LoggedAction>);
+
+impl ActionLog<'_> {
+    /// Only the first action recorded for a request is logged.
+    pub fn record(&self, home: &str, segment: Option<usize>, change_action: &ChangeAction) {
+        let _ = self.0.set(LoggedAction { home: home.to_string(), segment, change_action: change_action.clone() });
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ActionLog<'r> {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(ActionLog(request.local_cache(OnceLock::new)))
+    }
+}
+
+/// The route's path without its query, e.g. `/override/<id>`.
+pub fn route_name<'r>(request: &'r Request<'_>) -> &'r str {
+    request.route()
+        .map(|route| route.uri.as_str().split('?').next().unwrap_or_default())
+        .unwrap_or(UNMATCHED_ROUTE)
+}
+
+/// Which responses have their bodies logged.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BodyLogging {
+    Off,
+    /// Only responses that aren't 200 OK.
+    Errors,
+    All,
+}
+
+impl FromStr for BodyLogging {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(BodyLogging::Off),
+            "errors" => Ok(BodyLogging::Errors),
+            "all" => Ok(BodyLogging::All),
+            _ => Err(anyhow::anyhow!("Expected off, errors or all, not {s}.")),
+        }
+    }
+}
+
+pub struct AutoLogger {
+    bodies: BodyLogging,
+    body_max_bytes: usize,
+    redacted_fields: Vec<String>,
+}
+
+impl AutoLogger {
+    pub fn from_env(env_bodies_var: &str, env_body_max_bytes_var: &str, env_redacted_fields_var: &str) -> Self {
+        let bodies = match env::var(env_bodies_var).map(|s| s.parse()) {
+            Ok(Ok(bodies)) => bodies,
+            Ok(Err(e)) => {
+                warn!("Invalid {env_bodies_var}, so only logging error bodies: {e}");
+                BodyLogging::Errors
+            },
+            Err(_) => BodyLogging::Errors,
+        };
+        let body_max_bytes = match env::var(env_body_max_bytes_var).map(|s| s.parse()) {
+            Ok(Ok(max)) => max,
+            Ok(Err(e)) => {
+                warn!("Invalid {env_body_max_bytes_var}, so using {DEFAULT_BODY_MAX_BYTES}: {e}");
+                DEFAULT_BODY_MAX_BYTES
+            },
+            Err(_) => DEFAULT_BODY_MAX_BYTES,
+        };
+        let redacted_fields = env::var(env_redacted_fields_var)
+            .unwrap_or_else(|_| String::from(DEFAULT_REDACTED_FIELDS))
+            .split(',')
+            .map(|field| field.trim().to_lowercase())
+            .filter(|field| !field.is_empty())
+            .collect();
+
+        AutoLogger { bodies, body_max_bytes, redacted_fields }
+    }
+
+    fn logs_body(&self, status: Status) -> bool {
+        match self.bodies {
+            BodyLogging::Off => false,
+            BodyLogging::Errors => status != Status::Ok,
+            BodyLogging::All => true,
+        }
+    }
+
+    /// Redacts JSON bodies' sensitive fields, then truncates to `body_max_bytes`.
+    fn loggable_body(&self, body: &str) -> String {
+        let body = match serde_json::from_str::<Value>(body) {
+            Ok(mut json) => {
+                redact(&mut json, &self.redacted_fields);
+                json.to_string()
+            },
+            Err(_) => body.to_string(),
+        };
+        truncate(body, self.body_max_bytes)
+    }
+}
+
+fn redact(json: &mut Value, fields: &[String]) {
+    match json {
+        Value::Object(map) => for (key, value) in map.iter_mut() {
+            if fields.contains(&key.to_lowercase()) {
+                *value = Value::String(String::from(REDACTED));
+            } else {
+                redact(value, fields);
+            }
+        },
+        Value::Array(items) => items.iter_mut().for_each(|item| redact(item, fields)),
+        _ => {},
+    }
+}
+
+fn truncate(mut s: String, max_bytes: usize) -> String {
+    if s.len() <= max_bytes {
+        return s;
+    }
+    let total = s.len();
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s.truncate(end);
+    s.push_str(&format!("... ({total} bytes)"));
+    s
+}
+
+fn usable_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.chars().all(|c| c.is_ascii_graphic())
+}
 
 #[rocket::async_trait]
 impl Fairing for AutoLogger {
@@ -13,37 +188,84 @@ impl Fairing for AutoLogger {
         }
     }
 
-    async fn on_request(&self, _request: &mut Request<'_>, _: &mut Data<'_>) {
-        // Don't need to log since rocket will do that automatically
-        // if `log_level = "normal"` is set in Rocket.toml
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+        let id = request.headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|id| usable_request_id(id))
+            .map(String::from)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        request.local_cache(|| RequestId(id));
     }
 
     async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
-        let mut body_message = String::from("(no body)");
+        let request_id = request_id(request).0.clone();
+        response.set_raw_header(REQUEST_ID_HEADER, request_id.clone());
+        let latency_ms = request.local_cache(|| RequestStart(Instant::now())).0.elapsed().as_secs_f64() * 1000.;
+        let status = response.status();
 
-        let body = response.body_mut().to_string().await;
-        if let Ok(body) = body {
-            body_message = body.to_string();
+        let mut body_message = None;
+        if self.logs_body(status) {
+            // Streamed bodies (e.g. server-sent events) have no preset size and may never end,
+            // so reading them here would block the response forever.
+            if response.body().preset_size().is_none() {
+                body_message = Some(String::from("(streamed body)"));
+            } else if let Ok(body) = response.body_mut().to_string().await {
+                body_message = Some(self.loggable_body(&body));
 
-            // If we read the response body like we did in this function, we need to then
-            // call set_sized_body(). Otherwise, the client will receive no response body.
-            response.set_sized_body(body.len(), std::io::Cursor::new(body));
+                // If we read the response body like we did in this function, we need to then
+                // call set_sized_body(). Otherwise, the client will receive no response body.
+                response.set_sized_body(body.len(), std::io::Cursor::new(body));
+            }
         }
 
-        if response.status() == Status::Ok {
-            info!(
-                "Response for {} {}: {} {body_message}",
-                request.method(),
-                request.uri(),
-                response.status(),
-            );
-        } else {
-            warn!(
-                "Non-OK status: {} for {} {} {body_message}",
-                response.status(),
-                request.method(),
-                request.uri(),
-            );
-        }
+        let logged = request.local_cache(OnceLock::<LoggedAction>::new).get();
+        let (mirek, brightness) = match logged.map(|logged| &logged.change_action) {
+            Some(ChangeAction::Color { mirek, brightness }) => (Some(*mirek), Some(*brightness)),
+            _ => (None, None),
+        };
+
+        let level = if status == Status::Ok { log::Level::Info } else { log::Level::Warn };
+        log::log!(
+            level,
+            request_id = request_id.as_str(),
+            method = request.method().as_str(),
+            uri = request.uri().to_string().as_str(),
+            route = route_name(request),
+            status = status.code,
+            latency_ms = latency_ms,
+            error = matches!(status.class(), StatusClass::ClientError | StatusClass::ServerError),
+            home = logged.map(|logged| logged.home.as_str()),
+            segment = logged.and_then(|logged| logged.segment),
+            mirek = mirek,
+            brightness = brightness,
+            body = body_message.as_deref();
+            "Response for {} {}: {} in {latency_ms:.1}ms ({request_id}) {}",
+            request.method(),
+            request.uri(),
+            status,
+            body_message.as_deref().unwrap_or("(body not logged)"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::serde::json::json;
+    use super::{truncate, usable_request_id, AutoLogger, BodyLogging};
+
+    #[test]
+    fn test_loggable_body() {
+        let mut logger = AutoLogger { bodies: BodyLogging::All, body_max_bytes: 200, redacted_fields: vec![String::from("api_key")] };
+        let body = json!({ "api_key": "abc", "bridges": [{ "API_KEY": "def", "id": "1" }] }).to_string();
+        assert_eq!(logger.loggable_body(&body), r#"{"api_key":"[redacted]","bridges":[{"API_KEY":"[redacted]","id":"1"}]}"#);
+
+        logger.body_max_bytes = 10;
+        assert_eq!(logger.loggable_body(&body), r#"{"api_key"... (70 bytes)"#);
+        assert_eq!(logger.loggable_body("not json"), "not json");
+
+        assert_eq!(truncate(String::from("ééé"), 3), "é... (6 bytes)");
+        assert!(usable_request_id("5f0c-request"));
+        assert!(!usable_request_id("two words"));
     }
 }
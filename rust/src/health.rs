@@ -0,0 +1,128 @@
+//! The checks behind `/readyz`.
+
+use std::{env, time::Duration};
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+use rocket::{serde, tokio::time::timeout};
+
+use crate::{hue::HueClient, schedule::Schedule, sink::SinkStatuses};
+
+/// Probes usually give up after a few seconds, so don't wait on the bridge for longer than that.
+const BRIDGE_TIMEOUT: Duration = Duration::from_secs(3);
+/// One failed delivery is often a blip, and there's usually only one replica to take out of the Service.
+const DEFAULT_SINK_FAILURES: u32 = 3;
+
+/// How many failures in a row make a sink unready.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SinkReadiness {
+    /// `None` when failing sinks are only reported, never making `/readyz` fail.
+    max_failures: Option<u32>,
+}
+
+impl SinkReadiness {
+    /// `env_failures_var` is a number of failures in a row, or 0 to only report failing sinks.
+    pub fn from_env(env_failures_var: &str) -> Self {
+        let failures = match env::var(env_failures_var) {
+            Ok(s) => s.parse::<u32>().unwrap_or_else(|e| {
+                warn!("Invalid {env_failures_var} ({s}): {e}. Using {DEFAULT_SINK_FAILURES}.");
+                DEFAULT_SINK_FAILURES
+            }),
+            Err(_) => DEFAULT_SINK_FAILURES,
+        };
+        SinkReadiness { max_failures: (failures > 0).then_some(failures) }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: anyhow::Result<()>) -> Self {
+        Check { name: name.into(), ok: result.is_ok(), error: result.err().map(|e| format!("{e:#}")) }
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Readiness {
+    pub fn new(checks: Vec<Check>) -> Self {
+        Readiness { ready: checks.iter().all(|check| check.ok), checks }
+    }
+}
+
+/// Whether an action can be blended for `now`, which is what `/now` needs.
+pub fn check_schedule(schedule: &mut Schedule, now: DateTime<Tz>) -> Check {
+    let result = schedule.try_update(now).and_then(|_| schedule.get_action_for_now(&now)).map(|_| ());
+    Check::new("schedule", result)
+}
+
+pub async fn check_bridge(hue: &HueClient) -> Check {
+    let result = match timeout(BRIDGE_TIMEOUT, hue.grouped_lights()).await {
+        Ok(result) => result.map(|_| ()),
+        Err(_) => Err(anyhow::anyhow!("The bridge didn't answer within {BRIDGE_TIMEOUT:?}.")),
+    };
+    Check::new("bridge", result)
+}
+
+/// A sink is unready once it has failed `readiness` times in a row. Until then, its last error is still reported.
+pub fn check_sinks(statuses: &SinkStatuses, readiness: SinkReadiness) -> Vec<Check> {
+    statuses.all().into_iter().map(|status| {
+        let failures = status.consecutive_failures;
+        Check {
+            name: format!("sink:{}", status.kind),
+            ok: readiness.max_failures.is_none_or(|max| failures < max),
+            error: (failures > 0).then(|| format!("{failures} failures in a row, most recently: {}", status.last_error.unwrap_or_default())),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use chrono_tz::US::Eastern;
+    use rocket::serde::json::json;
+    use crate::{mock_bridge::MockBridge, sink::{SinkKind, SinkStatuses}};
+    use super::{check_bridge, check_sinks, Readiness, SinkReadiness};
+
+    #[rocket::async_test]
+    async fn test_checks() {
+        let bridge = MockBridge::start().await;
+        bridge.respond("GET", "/clip/v2/resource/grouped_light", 200, json!({ "errors": [], "data": [] }));
+        let statuses = SinkStatuses::default();
+        let hue = statuses.register(SinkKind::Hue);
+        statuses.register(SinkKind::Log);
+
+        let readiness = SinkReadiness { max_failures: Some(2) };
+        let ready = Readiness::new([vec![check_bridge(&bridge.client()).await], check_sinks(&statuses, readiness)].concat());
+        assert!(ready.ready);
+        assert_eq!(ready.checks.len(), 3);
+
+        // A single failure is reported, but doesn't make the sink unready.
+        let now = Eastern.with_ymd_and_hms(1999, 1, 1, 10, 0, 0).unwrap();
+        statuses.failed(hue, now, &anyhow::anyhow!("unreachable"));
+        let checks = check_sinks(&statuses, readiness);
+        assert!(checks[0].ok);
+        assert!(checks[0].error.is_some());
+
+        statuses.failed(hue, now, &anyhow::anyhow!("unreachable"));
+        assert!(check_sinks(&statuses, SinkReadiness { max_failures: None }).iter().all(|c| c.ok));
+        bridge.respond("GET", "/clip/v2/resource/grouped_light", 503, json!({ "errors": [{ "description": "busy" }], "data": [] }));
+
+        let not_ready = Readiness::new([vec![check_bridge(&bridge.client()).await], check_sinks(&statuses, readiness)].concat());
+        assert!(!not_ready.ready);
+        let failed: Vec<&str> = not_ready.checks.iter().filter(|c| !c.ok).map(|c| c.name.as_str()).collect();
+        assert_eq!(failed, ["bridge", "sink:hue"]);
+        assert!(not_ready.checks[1].error.as_deref().unwrap().contains("unreachable"));
+    }
+}
@@ -0,0 +1,151 @@
+use std::{env, fs, path::{Path, PathBuf}};
+
+use chrono::{DateTime, FixedOffset};
+use rocket::serde::{self, json::serde_json};
+use sha2::{Digest, Sha256};
+
+use crate::{reload::ReloadSource, store::BackgroundWriter};
+
+const DEFAULT_LIMIT: usize = 20;
+const HISTORY_FILE_NAME: &str = "schedule-history.json";
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub at: DateTime<FixedOffset>,
+    pub source: ReloadSource,
+    pub hash: String,
+    pub yaml: String,
+}
+
+/// A bounded, optionally persisted, list of every config that has been applied.
+#[derive(Debug)]
+pub struct ConfigHistory {
+    entries: Vec<HistoryEntry>,
+    limit: usize,
+    writer: Option<BackgroundWriter>,
+}
+
+impl ConfigHistory {
+    /// Kept in `sub_dir` of the data dir, which may be empty.
+    pub fn from_env(env_data_dir_var: &str, sub_dir: &Path, env_limit_var: &str) -> Self {
+        let limit = match env::var(env_limit_var) {
+            Ok(s) => s.parse::<usize>().unwrap_or_else(|e| {
+                warn!("Invalid {env_limit_var} ({s}): {e}. Using {DEFAULT_LIMIT}.");
+                DEFAULT_LIMIT
+            }),
+            Err(_) => DEFAULT_LIMIT,
+        };
+
+        match env::var(env_data_dir_var) {
+            Ok(dir) => Self::load(PathBuf::from(dir).join(sub_dir).join(HISTORY_FILE_NAME), limit),
+            Err(_) => {
+                info!("{env_data_dir_var} is not set, so schedule history won't survive restarts.");
+                Self::in_memory(limit)
+            },
+        }
+    }
+
+    pub fn in_memory(limit: usize) -> Self {
+        ConfigHistory { entries: Vec::new(), limit: limit.max(1), writer: None }
+    }
+
+    fn load(path: PathBuf, limit: usize) -> Self {
+        let entries = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable schedule history at {}: {e}", path.display());
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
+        ConfigHistory { entries, limit: limit.max(1), writer: Some(BackgroundWriter::new(path)) }
+    }
+
+    /// Only serializes the entries before returning. The file is written in the background.
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.write(serde_json::to_string_pretty(&self.entries)?);
+        }
+        Ok(())
+    }
+
+    /// Records a newly applied config, unless it's identical to the latest one.
+    pub fn record(&mut self, at: DateTime<FixedOffset>, source: ReloadSource, yaml: &str) {
+        let hash = content_hash(yaml);
+        if self.entries.last().is_some_and(|latest| latest.hash == hash) {
+            return;
+        }
+
+        let id = self.entries.last().map_or(1, |latest| latest.id + 1);
+        self.entries.push(HistoryEntry { id, at, source, hash, yaml: yaml.to_string() });
+        if self.entries.len() > self.limit {
+            self.entries.drain(..self.entries.len() - self.limit);
+        }
+
+        if let Err(e) = self.save() {
+            warn!("Unable to persist schedule history: {e:#}");
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+}
+
+impl Default for ConfigHistory {
+    fn default() -> Self {
+        Self::in_memory(DEFAULT_LIMIT)
+    }
+}
+
+fn content_hash(yaml: &str) -> String {
+    format!("{:x}", Sha256::digest(yaml.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use chrono::{DateTime, FixedOffset};
+    use crate::reload::ReloadSource;
+    use super::{ConfigHistory, HISTORY_FILE_NAME};
+
+    fn at() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("1999-01-01T10:00:00-05:00").unwrap()
+    }
+
+    #[test]
+    fn test_history_is_bounded_and_deduplicated() {
+        let mut history = ConfigHistory::in_memory(2);
+        history.record(at(), ReloadSource::Startup, "a");
+        history.record(at(), ReloadSource::Signal, "a");
+        assert_eq!(history.entries().len(), 1);
+
+        history.record(at(), ReloadSource::Api, "b");
+        history.record(at(), ReloadSource::FileWatch, "c");
+        let ids: Vec<u64> = history.entries().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(history.get(3).unwrap().yaml, "c");
+        assert!(history.get(1).is_none());
+    }
+
+    #[test]
+    fn test_history_persists() {
+        let dir = env::temp_dir().join(format!("rust-hue-history-test-{}", std::process::id()));
+        let path = dir.join(HISTORY_FILE_NAME);
+
+        let mut history = ConfigHistory::load(path.clone(), 5);
+        history.record(at(), ReloadSource::Startup, "a");
+        history.record(at(), ReloadSource::Api, "b");
+
+        let reloaded = ConfigHistory::load(path, 5);
+        assert_eq!(reloaded.entries(), history.entries());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
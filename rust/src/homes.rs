@@ -0,0 +1,189 @@
+//! Several households served by one deployment. Each home has its own schedule file, with its own location
+//! and timezone, and its own lock, so working out one home's day never waits on another's. Each also has its own
+//! apply loop, sinks, webhooks and stream, and its routes are served under `/homes/<id>/`. The default home's are
+//! served without the prefix too.
+//!
+//! Settings for where a home's lights are, such as `APPLY_SINKS`, `HUE_BRIDGE_BASE_URL` or `MQTT_BROKER_URL`, are
+//! read with the home's id as a prefix for every home but the default, e.g. `CABIN_APPLY_SINKS`.
+
+use std::{collections::BTreeMap, convert::Infallible, env, fmt, sync::Arc};
+
+use anyhow::Context;
+use rocket::{
+    http::Status,
+    request::{self, FromRequest},
+    tokio::sync::Mutex,
+    Request,
+};
+
+use crate::{events::ScheduleEvents, hue::HueClient, pair::Pairing, schedule::Schedule, sink::SinkStatuses};
+
+/// The id of the schedule from `SCHEDULE_YAML_PATH`, which the unprefixed routes use.
+pub const DEFAULT_HOME: &str = "default";
+/// Each home's routes are mounted under this, followed by its id.
+const HOMES_PATH: &str = "/homes";
+
+/// One household's schedule, and everything that follows it.
+#[derive(Clone)]
+pub struct Home {
+    pub id: String,
+    pub schedule: Arc<Mutex<Schedule>>,
+    pub events: ScheduleEvents,
+    /// The bridge this home's hue sink sends to.
+    pub hue: Option<HueClient>,
+    pub statuses: SinkStatuses,
+}
+
+impl Home {
+    pub fn new(id: &str, schedule: Schedule, events: ScheduleEvents, hue: Option<HueClient>) -> Self {
+        Home {
+            id: id.to_string(),
+            schedule: Arc::new(Mutex::new(schedule)),
+            events,
+            hue,
+            statuses: SinkStatuses::default(),
+        }
+    }
+
+    /// `var` itself for the default home, and prefixed with the id for the others, e.g. `CABIN_APPLY_SINKS`.
+    pub fn env_var(&self, var: &str) -> String {
+        env_var(&self.id, var)
+    }
+
+    /// Where this home's routes are mounted. The default home's are also mounted at `/`.
+    pub fn base(&self) -> String {
+        format!("{HOMES_PATH}/{}", self.id)
+    }
+}
+
+impl fmt::Display for Home {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "home {}", self.id)
+    }
+}
+
+fn env_var(id: &str, var: &str) -> String {
+    match id {
+        DEFAULT_HOME => var.to_string(),
+        id => format!("{}_{var}", id.to_uppercase().replace('-', "_")),
+    }
+}
+
+/// The home whose routes matched: the one named by the mount point, or the default home for unprefixed routes.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r Home {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let id = request.route()
+            .and_then(|route| route.uri.base().strip_prefix(HOMES_PATH))
+            .map_or(DEFAULT_HOME, |rest| rest.trim_start_matches('/'));
+        match request.rocket().state::<Homes>().and_then(|homes| homes.get(id)) {
+            Some(home) => request::Outcome::Success(home),
+            None => {
+                error!("No managed home {id} for {}.", request.uri());
+                request::Outcome::Forward(Status::InternalServerError)
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Homes {
+    homes: BTreeMap<String, Home>,
+}
+
+impl Homes {
+    /// `env_homes_var` lists `id=path` pairs separated by commas, e.g. `cabin=/data/cabin.yml`.
+    /// Ids may only contain lowercase letters, digits, `-` and `_`, since they're used in paths, URLs and env vars.
+    pub fn from_env(env_homes_var: &str, default: Home) -> anyhow::Result<Self> {
+        let poll_interval = default.events.poll_interval();
+        let mut homes = BTreeMap::from([(String::from(DEFAULT_HOME), default)]);
+        let Ok(s) = env::var(env_homes_var) else { return Ok(Homes { homes }) };
+
+        for (id, yaml_path) in parse_homes(&s).context(format!("Invalid {env_homes_var}"))? {
+            if homes.contains_key(&id) {
+                return Err(anyhow::anyhow!("Home {id} is defined more than once in {env_homes_var}."));
+            }
+            let schedule = Schedule::for_home(&id, yaml_path).context(format!("Unable to load the schedule for home {id}"))?;
+            // Only the default home's bridge can be paired through the API.
+            let hue = HueClient::from_env(
+                &env_var(&id, "HUE_BRIDGE_BASE_URL"),
+                &env_var(&id, "HUE_BRIDGE_API_KEY"),
+                &env_var(&id, "HUE_BRIDGE_CACERT_PEM_PATH"),
+                &env_var(&id, "HUE_BRIDGE_ID"),
+                &Pairing::default(),
+            ).context(format!("Unable to set up the bridge for home {id}"))?;
+            let home = Home::new(&id, schedule, ScheduleEvents::new(poll_interval), hue);
+            homes.insert(id, home);
+        }
+        Ok(Homes { homes })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Home> {
+        self.homes.get(id)
+    }
+
+    pub fn default_home(&self) -> &Home {
+        &self.homes[DEFAULT_HOME]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Home> {
+        self.homes.values()
+    }
+}
+
+fn parse_homes(s: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let homes = s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (id, path) = entry.split_once('=').context(format!("Expected id=path, not {entry}."))?;
+            let (id, path) = (id.trim(), path.trim());
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+                return Err(anyhow::anyhow!("Home ids may only contain a-z, 0-9, - and _, not {id:?}."));
+            }
+            if id == DEFAULT_HOME {
+                return Err(anyhow::anyhow!("{DEFAULT_HOME} is the home from SCHEDULE_YAML_PATH."));
+            }
+            if path.is_empty() {
+                return Err(anyhow::anyhow!("Home {id} has no schedule path."));
+            }
+            Ok((id.to_string(), path.to_string()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut prefixes = BTreeMap::new();
+    for (id, _) in &homes {
+        match prefixes.insert(env_var(id, ""), id) {
+            Some(other) if other != id => return Err(anyhow::anyhow!("Homes {other} and {id} would read the same env vars.")),
+            _ => {},
+        }
+    }
+    Ok(homes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{env_var, parse_homes, DEFAULT_HOME};
+
+    #[test]
+    fn test_parse_homes() {
+        assert_eq!(parse_homes(" cabin=/data/cabin.yml, city_2 = /data/city.yml ,").unwrap(), vec![
+            (String::from("cabin"), String::from("/data/cabin.yml")),
+            (String::from("city_2"), String::from("/data/city.yml")),
+        ]);
+        assert!(parse_homes("cabin").is_err());
+        assert!(parse_homes("Cabin=/data/cabin.yml").is_err());
+        assert!(parse_homes("../up=/data/cabin.yml").is_err());
+        assert!(parse_homes("default=/data/cabin.yml").is_err());
+        assert!(parse_homes("cabin=").is_err());
+        assert!(parse_homes("city-2=/data/a.yml,city_2=/data/b.yml").is_err());
+    }
+
+    #[test]
+    fn test_env_var() {
+        assert_eq!(env_var(DEFAULT_HOME, "APPLY_SINKS"), "APPLY_SINKS");
+        assert_eq!(env_var("city-2", "APPLY_SINKS"), "CITY_2_APPLY_SINKS");
+    }
+}
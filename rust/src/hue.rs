@@ -0,0 +1,506 @@
+use std::{collections::VecDeque, env, fs, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use reqwest::{header::ACCEPT, Certificate, Client, ClientBuilder, Method, Response, Url};
+use rocket::{
+    serde::{self, de::DeserializeOwned, json::serde_json, Serialize},
+    tokio::{sync::Mutex, time::{sleep_until, Instant}},
+};
+
+use crate::{pair::Pairing, schedule::ChangeAction};
+
+const API_KEY_HEADER: &str = "hue-application-key";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The bridge starts dropping requests at around 10 per second.
+const MIN_REQUEST_GAP: Duration = Duration::from_millis(100);
+/// Grouped lights fan out to every light in the group, so the bridge only takes about one of them per second.
+const MIN_GROUPED_LIGHT_GAP: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct ResourceRef {
+    pub rid: String,
+    pub rtype: String,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Metadata {
+    pub name: String,
+    pub archetype: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct On {
+    pub on: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Dimming {
+    /// Percentage, 0 to 100.
+    pub brightness: f64,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct MirekSchema {
+    pub mirek_minimum: u16,
+    pub mirek_maximum: u16,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct ColorTemperature {
+    /// `None` while the light is showing a color rather than a temperature.
+    pub mirek: Option<u16>,
+    /// The range this light supports. Only reported when reading a light, never sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirek_schema: Option<MirekSchema>,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Light {
+    pub id: String,
+    pub id_v1: Option<String>,
+    pub owner: ResourceRef,
+    pub metadata: Metadata,
+    pub on: On,
+    pub dimming: Option<Dimming>,
+    pub color_temperature: Option<ColorTemperature>,
+}
+
+/// A room or a zone.
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Group {
+    pub id: String,
+    pub children: Vec<ResourceRef>,
+    pub services: Vec<ResourceRef>,
+    pub metadata: Metadata,
+}
+
+impl Group {
+    pub fn grouped_light(&self) -> Option<&str> {
+        self.services.iter().find(|service| service.rtype == "grouped_light").map(|service| service.rid.as_str())
+    }
+
+    /// Rooms list their devices as children, while zones list the lights themselves.
+    pub fn contains(&self, light: &Light) -> bool {
+        self.children.iter().any(|child| child.rid == light.id || child.rid == light.owner.rid)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct GroupedLight {
+    pub id: String,
+    pub id_v1: Option<String>,
+    pub owner: ResourceRef,
+    pub on: Option<On>,
+    pub dimming: Option<Dimming>,
+}
+
+/// The body of a PUT to a light or grouped_light. Fields left as `None` aren't changed.
+#[derive(Debug, PartialEq, Clone, Default, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct LightUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on: Option<On>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimming: Option<Dimming>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_temperature: Option<ColorTemperature>,
+}
+
+impl LightUpdate {
+    /// `None` if the action leaves the lights alone.
+    pub fn from_action(change_action: &ChangeAction) -> Option<Self> {
+        match change_action {
+            ChangeAction::None => None,
+            ChangeAction::Color { mirek, brightness } => Some(LightUpdate {
+                on: None,
+                dimming: Some(Dimming { brightness: (*brightness).into() }),
+                color_temperature: Some(ColorTemperature { mirek: Some(*mirek), mirek_schema: None }),
+            }),
+        }
+    }
+}
+
+/// A change to a light or grouped_light, as reported by the event stream. Only the fields that changed are set.
+#[derive(Debug, PartialEq, Clone, serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ResourceUpdate {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub rtype: String,
+    pub on: Option<On>,
+    pub dimming: Option<Dimming>,
+    pub color_temperature: Option<ColorTemperature>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct HueEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    data: Vec<ResourceUpdate>,
+}
+
+/// Splits a server-sent event stream into the `data` of each event.
+#[derive(Debug, Default)]
+struct SseParser {
+    /// Chunks can end partway through a character, so lines are only decoded once they're complete.
+    buffer: Vec<u8>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(self.data.join("\n"));
+                    self.data.clear();
+                }
+            } else if let Some(data) = line.strip_prefix("data:") {
+                self.data.push(data.strip_prefix(' ').unwrap_or(data).to_string());
+            }
+            // Comments, ids and event names aren't used by the bridge.
+        }
+        events
+    }
+}
+
+/// The bridge's `/eventstream/clip/v2` feed.
+pub struct EventStream {
+    response: Response,
+    parser: SseParser,
+    pending: VecDeque<String>,
+}
+
+impl EventStream {
+    /// The updates in the next event, or `None` once the bridge closes the stream.
+    pub async fn next(&mut self) -> anyhow::Result<Option<Vec<ResourceUpdate>>> {
+        loop {
+            if let Some(data) = self.pending.pop_front() {
+                let events: Vec<HueEvent> = serde_json::from_str(&data).context(format!("Unexpected event: {data}"))?;
+                return Ok(Some(events
+                    .into_iter()
+                    .filter(|event| event.kind == "update")
+                    .flat_map(|event| event.data)
+                    .collect()));
+            }
+
+            let Some(chunk) = self.response.chunk().await.context("Event stream failed")? else {
+                return Ok(None);
+            };
+            self.pending.extend(self.parser.push(&chunk));
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct HueError {
+    description: String,
+}
+
+/// Every CLIP v2 response has this shape, even when the request failed.
+#[derive(Debug, serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct HueResponse<T> {
+    #[serde(default)]
+    errors: Vec<HueError>,
+    #[serde(default = "Vec::new")]
+    data: Vec<T>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct HueConfig {
+    pub base_url: String,
+    pub api_key: String,
+    /// PEM of the CA that signed the bridge's certificate. When set, it's the only CA trusted.
+    pub ca_cert_pem_path: Option<String>,
+    /// The bridge's certificate is issued to its id rather than its address.
+    pub bridge_id: Option<String>,
+}
+
+impl HueConfig {
+    /// Falls back to a bridge that was paired through `/bridge/pair`. Returns `None` if neither is configured.
+    pub fn from_env(
+        env_base_url_var: &str,
+        env_api_key_var: &str,
+        env_ca_cert_var: &str,
+        env_bridge_id_var: &str,
+        pairing: &Pairing,
+    ) -> anyhow::Result<Option<Self>> {
+        let Ok(base_url) = env::var(env_base_url_var) else {
+            let paired = pairing.load()?;
+            if paired.is_none() {
+                info!("{env_base_url_var} is not set and no bridge has been paired, so the Hue bridge won't be used.");
+            }
+            return Ok(paired);
+        };
+
+        Ok(Some(HueConfig {
+            base_url,
+            api_key: env::var(env_api_key_var).context(format!("{env_api_key_var} is required with {env_base_url_var}."))?,
+            ca_cert_pem_path: env::var(env_ca_cert_var).ok(),
+            bridge_id: env::var(env_bridge_id_var).ok(),
+        }))
+    }
+}
+
+/// A client that trusts only the configured CA, and the URL to reach the bridge at.
+pub fn client_builder(config: &HueConfig) -> anyhow::Result<(ClientBuilder, Url)> {
+    let mut base_url = Url::parse(&config.base_url).context(format!("Invalid bridge URL: {}", config.base_url))?;
+    let mut builder = Client::builder().connect_timeout(CONNECT_TIMEOUT);
+
+    if let Some(path) = &config.ca_cert_pem_path {
+        let pem = fs::read(path).context(format!("Unable to read {path}"))?;
+        let ca = Certificate::from_pem(&pem).context(format!("Unable to parse the certificate in {path}"))?;
+        builder = builder.tls_built_in_root_certs(false).add_root_certificate(ca);
+    }
+
+    // Connect to the configured address, but verify the certificate against the bridge id.
+    let ip = base_url.host_str().and_then(|host| host.trim_matches(['[', ']']).parse().ok());
+    if let (Some(bridge_id), Some(ip)) = (&config.bridge_id, ip) {
+        let bridge_id = bridge_id.to_lowercase();
+        let port = base_url.port_or_known_default().unwrap_or(443);
+        base_url.set_host(Some(&bridge_id)).context(format!("Invalid bridge id: {bridge_id}"))?;
+        builder = builder.resolve(&bridge_id, SocketAddr::new(ip, port));
+    }
+
+    Ok((builder, base_url))
+}
+
+/// A client for the bridge's CLIP v2 API.
+#[derive(Debug, Clone)]
+pub struct HueClient {
+    client: Client,
+    base_url: Url,
+    api_key: String,
+    /// When the next request may be sent. Shared by clones so every caller is throttled together.
+    next_request: Arc<Mutex<Instant>>,
+    /// When the next grouped_light PUT may be sent, on top of `next_request`.
+    next_grouped_light: Arc<Mutex<Instant>>,
+}
+
+impl HueClient {
+    pub fn new(config: &HueConfig) -> anyhow::Result<Self> {
+        let (builder, base_url) = client_builder(config)?;
+        Ok(HueClient {
+            client: builder.build().context("Unable to create the bridge HTTP client")?,
+            base_url,
+            api_key: config.api_key.clone(),
+            next_request: Arc::new(Mutex::new(Instant::now())),
+            next_grouped_light: Arc::new(Mutex::new(Instant::now())),
+        })
+    }
+
+    pub fn from_env(
+        env_base_url_var: &str,
+        env_api_key_var: &str,
+        env_ca_cert_var: &str,
+        env_bridge_id_var: &str,
+        pairing: &Pairing,
+    ) -> anyhow::Result<Option<Self>> {
+        let config = HueConfig::from_env(env_base_url_var, env_api_key_var, env_ca_cert_var, env_bridge_id_var, pairing)?;
+        config.as_ref().map(Self::new).transpose()
+    }
+
+    async fn throttle(next: &Mutex<Instant>, gap: Duration) {
+        let mut next = next.lock().await;
+        sleep_until(*next).await;
+        *next = Instant::now() + gap;
+    }
+
+    async fn request<T: DeserializeOwned, B: Serialize>(&self, method: Method, path: &str, body: Option<&B>) -> anyhow::Result<Vec<T>> {
+        Self::throttle(&self.next_request, MIN_REQUEST_GAP).await;
+        let url = self.base_url.join(&format!("clip/v2/resource/{path}"))?;
+        let mut request = self.client
+            .request(method.clone(), url)
+            .timeout(REQUEST_TIMEOUT)
+            .header(API_KEY_HEADER, &self.api_key);
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+
+        let response = request.send().await.context(format!("{method} {path} failed"))?;
+        let status = response.status();
+        let body: HueResponse<T> = response.json().await.context(format!("{method} {path} returned an unexpected body ({status})"))?;
+        if !body.errors.is_empty() || !status.is_success() {
+            let errors: Vec<String> = body.errors.into_iter().map(|e| e.description).collect();
+            return Err(anyhow::anyhow!("{method} {path} returned {status}: {}", errors.join("; ")));
+        }
+        Ok(body.data)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> anyhow::Result<Vec<T>> {
+        self.request(Method::GET, path, None::<&()>).await
+    }
+
+    pub async fn lights(&self) -> anyhow::Result<Vec<Light>> {
+        self.get("light").await
+    }
+
+    pub async fn rooms(&self) -> anyhow::Result<Vec<Group>> {
+        self.get("room").await
+    }
+
+    pub async fn zones(&self) -> anyhow::Result<Vec<Group>> {
+        self.get("zone").await
+    }
+
+    pub async fn grouped_lights(&self) -> anyhow::Result<Vec<GroupedLight>> {
+        self.get("grouped_light").await
+    }
+
+    /// Finds the room or zone called `name`.
+    pub async fn group(&self, name: &str) -> anyhow::Result<Group> {
+        let mut groups = self.zones().await?;
+        groups.extend(self.rooms().await?);
+
+        groups
+            .into_iter()
+            .find(|group| group.metadata.name == name)
+            .context(format!("No room or zone is called {name}."))
+    }
+
+    /// Opens the event stream. Unlike other requests, it has no timeout since it stays open.
+    pub async fn events(&self) -> anyhow::Result<EventStream> {
+        let url = self.base_url.join("eventstream/clip/v2")?;
+        let response = self.client
+            .get(url)
+            .header(API_KEY_HEADER, &self.api_key)
+            .header(ACCEPT, "text/event-stream")
+            .send()
+            .await
+            .context("Unable to open the event stream")?
+            .error_for_status()
+            .context("Unable to open the event stream")?;
+
+        Ok(EventStream { response, parser: SseParser::default(), pending: VecDeque::new() })
+    }
+
+    pub async fn update_light(&self, id: &str, update: &LightUpdate) -> anyhow::Result<()> {
+        self.request::<ResourceRef, _>(Method::PUT, &format!("light/{id}"), Some(update)).await?;
+        Ok(())
+    }
+
+    pub async fn update_grouped_light(&self, id: &str, update: &LightUpdate) -> anyhow::Result<()> {
+        Self::throttle(&self.next_grouped_light, MIN_GROUPED_LIGHT_GAP).await;
+        self.request::<ResourceRef, _>(Method::PUT, &format!("grouped_light/{id}"), Some(update)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::serde::json::json;
+    use crate::{mock_bridge::MockBridge, schedule::ChangeAction};
+    use super::{LightUpdate, SseParser};
+
+    #[rocket::async_test]
+    async fn test_list_resources() {
+        let bridge = MockBridge::start().await;
+        bridge.respond("GET", "/clip/v2/resource/light", 200, json!({
+            "errors": [],
+            "data": [{
+                "id": "light-1",
+                "id_v1": "/lights/1",
+                "owner": { "rid": "device-1", "rtype": "device" },
+                "metadata": { "name": "Lamp", "archetype": "sultan_bulb" },
+                "on": { "on": true },
+                "dimming": { "brightness": 50.0 },
+                "color_temperature": { "mirek": null },
+            }],
+        }));
+        bridge.respond("GET", "/clip/v2/resource/room", 200, json!({
+            "errors": [],
+            "data": [{
+                "id": "room-1",
+                "children": [{ "rid": "device-1", "rtype": "device" }],
+                "services": [{ "rid": "grouped-1", "rtype": "grouped_light" }],
+                "metadata": { "name": "Living room", "archetype": "living_room" },
+            }],
+        }));
+
+        let client = bridge.client();
+        let lights = client.lights().await.unwrap();
+        assert_eq!(lights[0].metadata.name, "Lamp");
+        assert_eq!(lights[0].color_temperature.as_ref().unwrap().mirek, None);
+        assert_eq!(client.rooms().await.unwrap()[0].services[0].rid, "grouped-1");
+
+        let request = &bridge.requests()[0];
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("GET", "/clip/v2/resource/light"));
+        assert_eq!(request.header("hue-application-key"), Some("test-key"));
+    }
+
+    #[rocket::async_test]
+    async fn test_update_grouped_light() {
+        let bridge = MockBridge::start().await;
+        bridge.respond("PUT", "/clip/v2/resource/grouped_light/grouped-1", 200, json!({
+            "errors": [],
+            "data": [{ "rid": "grouped-1", "rtype": "grouped_light" }],
+        }));
+        bridge.respond("PUT", "/clip/v2/resource/grouped_light/missing", 404, json!({
+            "errors": [{ "description": "Not Found" }],
+            "data": [],
+        }));
+
+        let client = bridge.client();
+        let update = LightUpdate::from_action(&ChangeAction::Color { mirek: 300, brightness: 40 }).unwrap();
+        client.update_grouped_light("grouped-1", &update).await.unwrap();
+        let error = client.update_grouped_light("missing", &update).await.unwrap_err();
+        assert!(error.to_string().contains("Not Found"));
+
+        let body = bridge.requests()[0].json();
+        assert_eq!(body, json!({ "dimming": { "brightness": 40.0 }, "color_temperature": { "mirek": 300 } }));
+        assert_eq!(LightUpdate::from_action(&ChangeAction::None), None);
+    }
+
+    #[test]
+    fn test_sse_parser() {
+        let mut parser = SseParser::default();
+        assert!(parser.push(b": hi\n\nid: 1\ndata: [1,").is_empty());
+        assert_eq!(parser.push(b"\r\ndata: 2]\r\n\r\ndata: []\n"), vec![String::from("[1,\n2]")]);
+        assert_eq!(parser.push(b"\n"), vec![String::from("[]")]);
+
+        // A character split across chunks.
+        let event = "data: \"Küche\"\n\n".as_bytes();
+        let split = event.iter().position(|&b| b == 0xc3).unwrap() + 1;
+        assert!(parser.push(&event[..split]).is_empty());
+        assert_eq!(parser.push(&event[split..]), vec![String::from("\"Küche\"")]);
+    }
+
+    #[rocket::async_test]
+    async fn test_event_stream() {
+        let bridge = MockBridge::start().await;
+        let event = json!([{
+            "creationtime": "2024-01-01T10:00:00Z",
+            "id": "event-1",
+            "type": "update",
+            "data": [{ "id": "light-1", "type": "light", "dimming": { "brightness": 20.0 } }],
+        }]);
+        bridge.respond_stream("/eventstream/clip/v2", format!(": hi\n\nid: 1\ndata: {event}\n\n"));
+
+        let mut events = bridge.client().events().await.unwrap();
+        let updates = events.next().await.unwrap().unwrap();
+        assert_eq!(updates[0].id, "light-1");
+        assert_eq!(updates[0].dimming.as_ref().unwrap().brightness, 20.0);
+        assert_eq!(updates[0].on, None);
+        assert!(events.next().await.unwrap().is_none());
+        assert_eq!(bridge.requests()[0].header("accept"), Some("text/event-stream"));
+    }
+}
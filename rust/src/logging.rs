@@ -0,0 +1,173 @@
+//! One JSON object per log line, for log collectors that parse them. Rocket's own logger is used otherwise.
+
+use std::{
+    env,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use chrono::{SecondsFormat, Utc};
+use log::{
+    kv::{self, Key, Value as KvValue, VisitSource, VisitValue},
+    Level, LevelFilter, Log, Metadata, Record,
+};
+use rocket::serde::json::{serde_json::{self, Map}, Value};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::anyhow!("Expected text or json, not {s}.")),
+        }
+    }
+}
+
+/// Installs the JSON logger if `env_format_var` is `json`, returning the format in use.
+/// Must be called before Rocket is built, since Rocket only installs its logger if none is set.
+pub fn init_from_env(env_format_var: &str, env_level_var: &str) -> LogFormat {
+    let format = match env::var(env_format_var) {
+        Ok(s) => s.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid {env_format_var}, so using text logs: {e}");
+            LogFormat::Text
+        }),
+        Err(_) => LogFormat::Text,
+    };
+    if format == LogFormat::Text {
+        return format;
+    }
+
+    let level = match env::var(env_level_var) {
+        Ok(s) => s.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid {env_level_var}, so logging at info: {e}");
+            LevelFilter::Info
+        }),
+        Err(_) => LevelFilter::Info,
+    };
+    if log::set_boxed_logger(Box::new(JsonLogger)).is_ok() {
+        log::set_max_level(level);
+    }
+    format
+}
+
+struct JsonLogger;
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_record(record);
+        let mut out: Box<dyn Write> = match record.level() {
+            Level::Error | Level::Warn => Box::new(io::stderr().lock()),
+            _ => Box::new(io::stdout().lock()),
+        };
+        let _ = writeln!(out, "{line}");
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+/// The record's key-values become fields next to `time`, `level`, `target` and `message`.
+fn format_record(record: &Record) -> String {
+    let mut fields = Map::new();
+    fields.insert(String::from("time"), Value::String(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)));
+    fields.insert(String::from("level"), Value::String(record.level().as_str().to_lowercase()));
+    // Rocket nests related lines under targets ending in `::_`.
+    fields.insert(String::from("target"), Value::String(record.target().trim_end_matches("::_").to_string()));
+    fields.insert(String::from("message"), Value::String(record.args().to_string()));
+    let _ = record.key_values().visit(&mut FieldVisitor(&mut fields));
+    serde_json::to_string(&Value::Object(fields)).unwrap_or_default()
+}
+
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for FieldVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: KvValue<'kvs>) -> Result<(), kv::Error> {
+        let mut json = JsonValue(Value::Null);
+        value.visit(&mut json)?;
+        self.0.insert(key.to_string(), json.0);
+        Ok(())
+    }
+}
+
+/// Keeps numbers and bools as they are, and writes anything else as a string. `None` becomes null.
+struct JsonValue(Value);
+
+impl<'v> VisitValue<'v> for &mut JsonValue {
+    fn visit_any(&mut self, value: KvValue) -> Result<(), kv::Error> {
+        self.0 = Value::String(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = Value::from(value);
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = Value::from(value);
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = Value::from(value);
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = Value::Bool(value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::{kv::Value as KvValue, Level, Record};
+    use rocket::serde::json::{serde_json, Value};
+    use super::format_record;
+
+    #[test]
+    fn test_format_record() {
+        let fields: [(&str, KvValue); 4] = [
+            ("status", KvValue::from(404u16)),
+            ("route", KvValue::from("/override/<id>")),
+            ("latency_ms", KvValue::from(1.5)),
+            ("mirek", KvValue::null()),
+        ];
+        let line = format_record(&Record::builder()
+            .level(Level::Warn)
+            .target("rocket::server::_")
+            .args(format_args!("Not found"))
+            .key_values(&fields)
+            .build());
+
+        let json: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["level"], "warn");
+        assert_eq!(json["target"], "rocket::server");
+        assert_eq!(json["message"], "Not found");
+        assert_eq!(json["status"], 404);
+        assert_eq!(json["route"], "/override/<id>");
+        assert_eq!(json["latency_ms"], 1.5);
+        assert_eq!(json["mirek"], Value::Null);
+    }
+}
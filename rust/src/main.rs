@@ -1,24 +1,83 @@
+mod actions;
+mod apply;
+mod audit;
+mod auth;
+mod capabilities;
 mod schedule;
 mod sunset;
 mod time;
 mod fairing;
+mod events;
+mod health;
+mod history;
+mod homes;
+mod hue;
+mod logging;
+mod metrics;
+mod mqtt;
+mod openapi;
+mod overrides;
+mod pair;
+mod pause;
+mod reload;
+mod sink;
+mod store;
+mod telemetry;
+mod v2;
+mod webhooks;
+mod ws;
 
-#[macro_use] extern crate rocket;
+#[cfg(test)]
+mod mock_bridge;
+#[cfg(test)]
+mod mock_broker;
 
-use std::sync::Arc;
+#[macro_use] extern crate rocket;
 
-use chrono::DateTime;
+use chrono::{DateTime, FixedOffset};
 use chrono_tz::Tz;
-use rocket::{serde::{self, json::Json}, tokio::sync::Mutex, State};
+use rocket::{
+    http::{Header, Status},
+    response::stream::{Event, EventStream},
+    serde::{self, json::Json},
+    tokio::select,
+    Shutdown, State,
+};
 
-use schedule::Schedule;
+use actions::Actions;
+use audit::{AuditEntry, AuditEvent, AuditLog, Caller};
+use auth::{AdminAccess, ApiTokens, OverrideAccess, ReadAccess, StreamOverrideAccess, StreamReadAccess};
+use events::{ChangeReason, ScheduleEvents};
+use fairing::ActionLog;
+use overrides::{Override, OverrideRequest};
+use pair::{DiscoveredBridge, PairRequest, PairResponse, Pairing};
+use pause::Pause;
+use health::{Readiness, SinkReadiness};
+use history::HistoryEntry;
+use homes::{Home, Homes, DEFAULT_HOME};
+use hue::{Group, GroupedLight, HueClient, Light, LightUpdate};
+use logging::LogFormat;
+use metrics::{HomeSnapshot, Metrics};
+use openapi::ApiDoc;
+use reload::ReloadSource;
+use schedule::{Schedule, ScheduleYamlConfig, UploadResult};
+use sink::{SinkKind, SinkStatus};
+use telemetry::{RequestSpan, Telemetry};
+use utoipa::OpenApi;
+use utoipa_rapidoc::RapiDoc;
+use webhooks::{Delivery, Webhooks};
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde", rename_all = "snake_case")]
 struct StatusBody {
     up: bool,
 }
 
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The server is up.", body = StatusBody),
+    ),
+)]
 #[get("/")]
 fn index() -> Json<StatusBody> {
     Json(StatusBody {
@@ -26,7 +85,69 @@ fn index() -> Json<StatusBody> {
     })
 }
 
-#[derive(Debug, serde::Serialize)]
+/// Only says the process is answering. See `/readyz` for whether it's working.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The server is up.", body = StatusBody),
+    ),
+)]
+#[get("/healthz")]
+fn healthz() -> Json<StatusBody> {
+    Json(StatusBody {
+        up: true,
+    })
+}
+
+#[derive(Responder)]
+enum ReadinessResponse {
+    #[response(status = 200)]
+    Ready(Json<Readiness>),
+    #[response(status = 503)]
+    NotReady(Json<Readiness>),
+}
+
+/// Checks that every home's action can be blended for now, its bridge answers if its hue sink is in use, and none of
+/// its sinks has failed `READY_SINK_FAILURES` times in a row. Checks for homes other than the default are prefixed
+/// with the home's id, e.g. `cabin/schedule`.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Every check passed.", body = Readiness),
+        (status = 503, description = "At least one check failed.", body = Readiness),
+    ),
+)]
+#[get("/readyz")]
+#[tracing::instrument(parent = &span.0, skip_all)]
+async fn readyz(span: RequestSpan, homes: &State<Homes>, sink_readiness: &State<SinkReadiness>) -> ReadinessResponse {
+    let mut checks = Vec::new();
+    for home in homes.iter() {
+        let mut home_checks = {
+            let mut schedule = home.schedule.lock().await;
+            let now = schedule.now();
+            vec![health::check_schedule(&mut schedule, now)]
+        };
+        // A paired bridge that nothing sends to shouldn't hold up readiness.
+        let hue_sink = home.statuses.all().iter().any(|status| status.kind == SinkKind::Hue);
+        if let (Some(hue), true) = (&home.hue, hue_sink) {
+            home_checks.push(health::check_bridge(hue).await);
+        }
+        home_checks.extend(health::check_sinks(&home.statuses, *sink_readiness.inner()));
+
+        if home.id != DEFAULT_HOME {
+            for check in &mut home_checks {
+                check.name = format!("{}/{}", home.id, check.name);
+            }
+        }
+        checks.extend(home_checks);
+    }
+
+    let readiness = Readiness::new(checks);
+    match readiness.ready {
+        true => ReadinessResponse::Ready(Json(readiness)),
+        false => ReadinessResponse::NotReady(Json(readiness)),
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde", rename_all = "snake_case")]
 struct ErrorBody {
     error: String,
@@ -36,6 +157,10 @@ struct ErrorBody {
 enum Responses<T> {
     #[response(status = 400)]
     Bad(Json<ErrorBody>),
+    #[response(status = 401)]
+    Unauthorized(Json<ErrorBody>, Header<'static>),
+    #[response(status = 403)]
+    Forbidden(Json<ErrorBody>),
     #[response(status = 200)]
     Good(Json<T>),
 }
@@ -45,64 +170,743 @@ impl<T> Responses<T> {
         Responses::Bad(Json(ErrorBody { error: s }))
     }
 
+    fn unauthorized(s: String) -> Responses<T> {
+        Responses::Unauthorized(Json(ErrorBody { error: s }), Header::new("WWW-Authenticate", "Bearer"))
+    }
+
+    fn forbidden(s: String) -> Responses<T> {
+        Responses::Forbidden(Json(ErrorBody { error: s }))
+    }
+
     fn good(t: T) -> Responses<T> {
         Responses::Good(Json(t))
     }
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde", rename_all = "snake_case")]
 struct NowResponse {
     now: DateTime<Tz>,
     change_action: schedule::ChangeAction,
     just_updated: bool,
+    override_id: Option<u64>,
+    paused: bool,
+    paused_until: Option<DateTime<Tz>>,
 }
 
-#[get("/now")]
-async fn now(state: &State<Arc<Mutex<Schedule>>>) -> Responses<NowResponse> {
-    let mut guard = state.lock().await;
+/// Blends the action for now at `home`, and hands it to the request's log line.
+async fn current_action(home: &Home, log: &ActionLog<'_>) -> Result<NowResponse, String> {
+    let mut guard = home.schedule.lock().await;
     let now = (*guard).now();
-    let updated = match (*guard).try_update(now) {
-        Ok(o) => o,
-        Err(e) => return Responses::bad(e.to_string())
-    };
+    let (updated, change_action) = (*guard).update_and_get_action(&now).map_err(|e| e.to_string())?;
+    log.record(&home.id, (*guard).segment_index(&now).ok(), &change_action);
 
-    let change_action = match (*guard).get_action_for_now(&now) {
-        Ok(o) => o,
-        Err(e) => return Responses::bad(e.to_string()),
-    };
+    let override_id = (*guard).active_override(&now).map(|o| o.id);
+    let pause = (*guard).active_pause(&now);
+
+    Ok(NowResponse {
+        now,
+        change_action,
+        just_updated: updated,
+        override_id,
+        paused: pause.is_some(),
+        paused_until: pause.and_then(|p| p.until),
+    })
+}
+
+/// Records a home's action in the audit log, if it changed.
+fn audit_now(home: &Home, response: &NowResponse, caller: &Caller, audit: &AuditLog) {
+    let entry = AuditEntry::new(response.now, AuditEvent::Now, caller)
+        .with_home(&home.id)
+        .with_action(response.change_action.clone());
+    audit.record_now(if response.paused {
+        entry.with_detail("paused")
+    } else if let Some(id) = response.override_id {
+        entry.with_detail(format!("override {id}"))
+    } else {
+        entry
+    });
+}
+
+fn now_v2_body(response: NowResponse) -> v2::Now {
+    v2::Now {
+        schema_version: v2::SCHEMA_VERSION,
+        now: response.now,
+        change_action: v2::Action::from(&response.change_action),
+        just_updated: response.just_updated,
+        override_id: response.override_id,
+        paused: response.paused,
+        paused_until: response.paused_until,
+    }
+}
+
+/// The v1 shape, kept for existing clients. See `/v2/now`.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The blended action for now.", body = NowResponse),
+        (status = 400, description = "The schedule couldn't be blended for now, e.g. the sunset time couldn't be worked out.", body = ErrorBody),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/now")]
+#[tracing::instrument(parent = &span.0, skip_all, fields(home = %home.id))]
+async fn now(
+    _access: ReadAccess,
+    span: RequestSpan,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+    metrics: &State<Metrics>,
+    log: ActionLog<'_>,
+) -> Responses<NowResponse> {
+    match current_action(home, &log).await {
+        Ok(response) => {
+            audit_now(home, &response, &caller, audit);
+            Responses::good(response)
+        },
+        Err(e) => {
+            metrics.count_error(&home.id, "schedule");
+            Responses::bad(e)
+        },
+    }
+}
+
+/// The blended action for now, with `change_action` tagged by `type`.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The blended action for now.", body = v2::Now),
+        (status = 400, description = "The schedule couldn't be blended for now, e.g. the sunset time couldn't be worked out.", body = ErrorBody),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/v2/now")]
+#[tracing::instrument(parent = &span.0, skip_all, fields(home = %home.id))]
+async fn now_v2(
+    _access: ReadAccess,
+    span: RequestSpan,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+    metrics: &State<Metrics>,
+    log: ActionLog<'_>,
+) -> Responses<v2::Now> {
+    match current_action(home, &log).await {
+        Ok(response) => {
+            audit_now(home, &response, &caller, audit);
+            Responses::good(now_v2_body(response))
+        },
+        Err(e) => {
+            metrics.count_error(&home.id, "schedule");
+            Responses::bad(e)
+        },
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+struct HomeBody {
+    id: String,
+    timezone: String,
+    now: DateTime<Tz>,
+}
 
-    Responses::good(NowResponse { now, change_action, just_updated: updated })
+/// Every home, including `default`, which is the one the unprefixed routes use. Every route that reads or changes a
+/// schedule is also served under `/homes/<id>`, e.g. `/homes/cabin/override`.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Every home.", body = [HomeBody]),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/homes")]
+async fn list_homes(_access: ReadAccess, homes: &State<Homes>) -> Json<Vec<HomeBody>> {
+    let mut bodies = Vec::new();
+    for home in homes.iter() {
+        let now = home.schedule.lock().await.now();
+        bodies.push(HomeBody { id: home.id.clone(), timezone: now.timezone().name().to_string(), now });
+    }
+    Json(bodies)
+}
+
+/// Server-sent events whenever the blended action changes. Since `EventSource` can't set headers, the token can
+/// also be given as `access_token`.
+#[utoipa::path(
+    params(("access_token" = Option<String>, Query, description = "The bearer token, for clients that can't set headers.")),
+    responses(
+        (status = 200, description = "`change_action` events with a `StreamEvent`, and `error` events.", content_type = "text/event-stream", body = String),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/stream")]
+fn stream(_access: StreamReadAccess, home: &Home, mut shutdown: Shutdown) -> EventStream![] {
+    let mut subscription = home.events.stream();
+
+    EventStream! {
+        loop {
+            let outcome = select! {
+                outcome = subscription.next() => match outcome {
+                    Some(outcome) => outcome,
+                    None => break,
+                },
+                _ = &mut shutdown => break,
+            };
+
+            yield match outcome {
+                Ok(event) => Event::json(&event).event("change_action"),
+                Err(error) => Event::json(&ErrorBody { error }).event("error"),
+            };
+        }
+    }
+}
+
+/// The WebSocket control channel. Since `WebSocket` can't set headers, the token can also be given as `access_token`,
+/// or by offering the subprotocols `bearer` and the token, e.g. `new WebSocket(url, ["bearer", token])`.
+#[utoipa::path(
+    params(("access_token" = Option<String>, Query, description = "The bearer token, for clients that can't set headers.")),
+    responses(
+        (status = 101, description = "Switches to the WebSocket control channel."),
+    ),
+    security(("bearer" = ["override"])),
+)]
+#[get("/ws")]
+fn control_channel(
+    access: StreamOverrideAccess,
+    ws: rocket_ws::WebSocket,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+    shutdown: Shutdown,
+) -> ws::Upgrade {
+    let channel = ws::channel(ws, caller, home.clone(), audit.inner().clone(), shutdown);
+    ws::Upgrade { channel, bearer_protocol: access.bearer_protocol }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+struct DebugBody {
+    #[serde(flatten)]
+    schedule: schedule::DebugInfo,
+    sinks: Vec<SinkStatus>,
 }
 
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The schedule's internals and the sinks' statuses.", body = DebugBody),
+        (status = 400, description = "The schedule couldn't be blended for now, so there are no internals to show.", body = ErrorBody),
+    ),
+    security(("bearer" = ["read"])),
+)]
 #[get("/debug")]
-async fn get_debug_info(state: &State<Arc<Mutex<Schedule>>>) -> Responses<schedule::DebugInfo> {
-    let mut guard = state.lock().await;
+#[tracing::instrument(parent = &span.0, skip_all, fields(home = %home.id))]
+async fn get_debug_info(
+    _access: ReadAccess,
+    span: RequestSpan,
+    home: &Home,
+    metrics: &State<Metrics>,
+    log: ActionLog<'_>,
+) -> Responses<DebugBody> {
+    let mut guard = home.schedule.lock().await;
 
     // get_debug_info() will automatically update
     let debug_info = match (*guard).get_debug_info() {
         Ok(o) => o,
-        Err(e) => return Responses::bad(e.to_string()),
+        Err(e) => {
+            metrics.count_error(&home.id, "schedule");
+            return Responses::bad(e.to_string());
+        },
     };
+    log.record(&home.id, (*guard).segment_index(&debug_info.now()).ok(), debug_info.change_action());
 
-    Responses::good(debug_info)
+    Responses::good(DebugBody { schedule: debug_info, sinks: home.statuses.all() })
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde", rename_all = "snake_case")]
 struct ForceUpdateBody {
     just_updated: bool,
 }
 
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Today's schedule was recomputed.", body = ForceUpdateBody),
+        (status = 400, description = "Today's schedule couldn't be recomputed, e.g. the sunset time couldn't be worked out.", body = ErrorBody),
+    ),
+    security(("bearer" = ["override"])),
+)]
 #[put("/force-update")]
-async fn force_update(state: &State<Arc<Mutex<Schedule>>>) -> Responses<ForceUpdateBody> {
-    let mut guard = state.lock().await;
+#[tracing::instrument(parent = &span.0, skip_all, fields(home = %home.id))]
+async fn force_update(
+    _access: OverrideAccess,
+    span: RequestSpan,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+    metrics: &State<Metrics>,
+) -> Responses<ForceUpdateBody> {
+    let actions = Actions { caller: &caller, home, audit };
+    match actions.force_update().await {
+        Ok(()) => Responses::good(ForceUpdateBody { just_updated: true }),
+        Err(e) => {
+            metrics.count_error(&home.id, "schedule");
+            Responses::bad(e.to_string())
+        },
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+struct OverridesBody {
+    active_id: Option<u64>,
+    overrides: Vec<Override>,
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Every override, and which one is active.", body = OverridesBody),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/override")]
+async fn list_overrides(_access: ReadAccess, home: &Home) -> Responses<OverridesBody> {
+    let guard = home.schedule.lock().await;
+    let now = (*guard).now();
+
+    Responses::good(OverridesBody {
+        active_id: (*guard).active_override(&now).map(|o| o.id),
+        overrides: (*guard).overrides().to_vec(),
+    })
+}
+
+#[utoipa::path(
+    request_body = OverrideRequest,
+    responses(
+        (status = 200, description = "The override that was added.", body = Override),
+        (status = 400, description = "The brightness isn't between 0 and 100, or the override would already have expired.", body = ErrorBody),
+    ),
+    security(("bearer" = ["override"])),
+)]
+#[post("/override", data = "<request>")]
+async fn add_override(
+    _access: OverrideAccess,
+    request: Json<OverrideRequest>,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+) -> Responses<Override> {
+    let actions = Actions { caller: &caller, home, audit };
+    match actions.add_override(&request).await {
+        Ok(added) => Responses::good(added),
+        Err(e) => Responses::bad(e.to_string()),
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+struct CancelOverridesBody {
+    cancelled: usize,
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "How many overrides were cancelled.", body = CancelOverridesBody),
+    ),
+    security(("bearer" = ["override"])),
+)]
+#[delete("/override")]
+async fn cancel_all_overrides(
+    _access: OverrideAccess,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+) -> Responses<CancelOverridesBody> {
+    let actions = Actions { caller: &caller, home, audit };
+    match actions.cancel_overrides(None).await {
+        Ok(cancelled) => Responses::good(CancelOverridesBody { cancelled }),
+        Err(e) => Responses::bad(e.to_string()),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The override was cancelled.", body = CancelOverridesBody),
+        (status = 400, description = "No override has that id.", body = ErrorBody),
+    ),
+    security(("bearer" = ["override"])),
+)]
+#[delete("/override/<id>")]
+async fn cancel_override(
+    _access: OverrideAccess,
+    id: u64,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+) -> Responses<CancelOverridesBody> {
+    let actions = Actions { caller: &caller, home, audit };
+    match actions.cancel_overrides(Some(id)).await {
+        Ok(cancelled) => Responses::good(CancelOverridesBody { cancelled }),
+        Err(e) => Responses::bad(e.to_string()),
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+struct PauseBody {
+    paused: bool,
+    pause: Option<Pause>,
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The schedule is paused.", body = PauseBody),
+        (status = 400, description = "The pause would already have ended.", body = ErrorBody),
+    ),
+    security(("bearer" = ["override"])),
+)]
+#[put("/pause?<minutes>&<by>")]
+async fn pause_schedule(
+    _access: OverrideAccess,
+    minutes: Option<u32>,
+    by: Option<String>,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+) -> Responses<PauseBody> {
+    let actions = Actions { caller: &caller, home, audit };
+    match actions.pause(minutes, by).await {
+        Ok(pause) => Responses::good(PauseBody { paused: true, pause: Some(pause) }),
+        Err(e) => Responses::bad(e.to_string()),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The schedule resumed, or will.", body = PauseBody),
+        (status = 400, description = "`after_minutes` was given while the schedule isn't paused.", body = ErrorBody),
+    ),
+    security(("bearer" = ["override"])),
+)]
+#[put("/resume?<after_minutes>")]
+async fn resume_schedule(
+    _access: OverrideAccess,
+    after_minutes: Option<u32>,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+) -> Responses<PauseBody> {
+    let actions = Actions { caller: &caller, home, audit };
+    match actions.resume(after_minutes).await {
+        Ok(pause) => Responses::good(PauseBody { paused: pause.is_some(), pause }),
+        Err(e) => Responses::bad(e.to_string()),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The config in use.", body = ScheduleYamlConfig),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/schedule")]
+async fn get_schedule(_access: ReadAccess, home: &Home) -> Json<ScheduleYamlConfig> {
+    Json(home.schedule.lock().await.config())
+}
+
+/// Accepts the same YAML as the schedule file, or its JSON equivalent.
+#[utoipa::path(
+    request_body(description = "The schedule as YAML, or its JSON equivalent.", content((String = "application/yaml"), (ScheduleYamlConfig = "application/json"))),
+    responses(
+        (status = 200, description = "The config was valid, and applied unless `dry_run` was set.", body = UploadResult),
+        (status = 400, description = "The config couldn't be parsed, or doesn't work for today.", body = ErrorBody),
+    ),
+    security(("bearer" = ["admin"])),
+)]
+#[put("/schedule?<dry_run>", data = "<body>")]
+#[tracing::instrument(parent = &span.0, skip_all, fields(home = %home.id))]
+#[allow(clippy::too_many_arguments)]
+async fn upload_schedule(
+    _access: AdminAccess,
+    dry_run: Option<bool>,
+    body: String,
+    span: RequestSpan,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+) -> Responses<UploadResult> {
+    let dry_run = dry_run.unwrap_or(false);
+    let mut guard = home.schedule.lock().await;
     let now = (*guard).now();
-    if let Err(e) = (*guard).set_today(&now) {
-        return Responses::bad(e.to_string())
+    let result = match (*guard).upload(&body, now, dry_run) {
+        Ok(o) => o,
+        Err(e) => return Responses::bad(format!("{e:#}")),
+    };
+    if !dry_run {
+        home.events.notify(ChangeReason::Reload);
+        audit.record(AuditEntry::new(now, AuditEvent::Reload, &caller).with_home(&home.id).with_detail(ReloadSource::Api.to_string()));
+    }
+
+    Responses::good(result)
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Configs that were applied, oldest first.", body = [HistoryEntry]),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/schedule/history")]
+async fn get_schedule_history(_access: ReadAccess, home: &Home) -> Json<Vec<HistoryEntry>> {
+    Json(home.schedule.lock().await.history().to_vec())
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The config that was restored.", body = ScheduleYamlConfig),
+        (status = 400, description = "No history entry has that id, or its config doesn't work for today.", body = ErrorBody),
+    ),
+    security(("bearer" = ["admin"])),
+)]
+#[post("/schedule/rollback/<id>")]
+async fn rollback_schedule(
+    _access: AdminAccess,
+    id: u64,
+    caller: Caller,
+    home: &Home,
+    audit: &State<AuditLog>,
+) -> Responses<ScheduleYamlConfig> {
+    let mut guard = home.schedule.lock().await;
+    let now = (*guard).now();
+    if let Err(e) = (*guard).rollback(id, now) {
+        return Responses::bad(format!("{e:#}"));
+    }
+    home.events.notify(ChangeReason::Reload);
+    audit.record(AuditEntry::new(now, AuditEvent::Reload, &caller)
+        .with_home(&home.id)
+        .with_detail(format!("{} to {id}", ReloadSource::Rollback)));
+
+    Responses::good((*guard).config())
+}
+
+const NO_BRIDGE: &str = "No Hue bridge is configured. Set HUE_BRIDGE_BASE_URL and HUE_BRIDGE_API_KEY, or pair with one.";
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The bridge's lights.", body = [Light]),
+        (status = 400, description = "No bridge is configured, or it couldn't be reached or sent something unexpected.", body = ErrorBody),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/bridge/lights")]
+async fn bridge_lights(_access: ReadAccess, hue: &State<Option<HueClient>>) -> Responses<Vec<Light>> {
+    let Some(hue) = hue.inner() else { return Responses::bad(NO_BRIDGE.to_string()) };
+    match hue.lights().await {
+        Ok(lights) => Responses::good(lights),
+        Err(e) => Responses::bad(format!("{e:#}")),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The bridge's rooms.", body = [Group]),
+        (status = 400, description = "No bridge is configured, or it couldn't be reached or sent something unexpected.", body = ErrorBody),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/bridge/rooms")]
+async fn bridge_rooms(_access: ReadAccess, hue: &State<Option<HueClient>>) -> Responses<Vec<Group>> {
+    let Some(hue) = hue.inner() else { return Responses::bad(NO_BRIDGE.to_string()) };
+    match hue.rooms().await {
+        Ok(rooms) => Responses::good(rooms),
+        Err(e) => Responses::bad(format!("{e:#}")),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The bridge's zones.", body = [Group]),
+        (status = 400, description = "No bridge is configured, or it couldn't be reached or sent something unexpected.", body = ErrorBody),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/bridge/zones")]
+async fn bridge_zones(_access: ReadAccess, hue: &State<Option<HueClient>>) -> Responses<Vec<Group>> {
+    let Some(hue) = hue.inner() else { return Responses::bad(NO_BRIDGE.to_string()) };
+    match hue.zones().await {
+        Ok(zones) => Responses::good(zones),
+        Err(e) => Responses::bad(format!("{e:#}")),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The bridge's grouped lights.", body = [GroupedLight]),
+        (status = 400, description = "No bridge is configured, or it couldn't be reached or sent something unexpected.", body = ErrorBody),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/bridge/grouped_lights")]
+async fn bridge_grouped_lights(_access: ReadAccess, hue: &State<Option<HueClient>>) -> Responses<Vec<GroupedLight>> {
+    let Some(hue) = hue.inner() else { return Responses::bad(NO_BRIDGE.to_string()) };
+    match hue.grouped_lights().await {
+        Ok(grouped_lights) => Responses::good(grouped_lights),
+        Err(e) => Responses::bad(format!("{e:#}")),
+    }
+}
+
+/// Sends `update` straight to a `light` or `grouped_light`, bypassing the schedule.
+#[utoipa::path(
+    request_body = LightUpdate,
+    responses(
+        (status = 200, description = "The update that was sent.", body = LightUpdate),
+        (status = 400, description = "No bridge is configured, `rtype` isn't light or grouped_light, or the bridge rejected the update.", body = ErrorBody),
+    ),
+    security(("bearer" = ["admin"])),
+)]
+#[put("/bridge/<rtype>/<id>", data = "<update>")]
+#[allow(clippy::too_many_arguments)]
+async fn bridge_update(
+    _access: AdminAccess,
+    rtype: &str,
+    id: &str,
+    update: Json<LightUpdate>,
+    caller: Caller,
+    hue: &State<Option<HueClient>>,
+    homes: &State<Homes>,
+    audit: &State<AuditLog>,
+) -> Responses<LightUpdate> {
+    let Some(hue) = hue.inner() else { return Responses::bad(NO_BRIDGE.to_string()) };
+    let result = match rtype {
+        "light" => hue.update_light(id, &update).await,
+        "grouped_light" => hue.update_grouped_light(id, &update).await,
+        _ => return Responses::bad(format!("Only light and grouped_light can be updated, not {rtype}.")),
+    };
+    if let Err(e) = result {
+        return Responses::bad(format!("{e:#}"));
     }
 
-    Responses::good(ForceUpdateBody { just_updated: true })
+    let now = homes.default_home().schedule.lock().await.now();
+    audit.record(AuditEntry::new(now, AuditEvent::BridgeUpdate, &caller).with_detail(format!("{rtype} {id}")));
+    Responses::good(update.into_inner())
+}
+
+/// Bridges that answer on mDNS, which takes a few seconds.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "The bridges that answered.", body = [DiscoveredBridge]),
+        (status = 400, description = "mDNS couldn't be started. No bridges answering isn't an error, just an empty list.", body = ErrorBody),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/bridge/discover")]
+async fn bridge_discover(_access: ReadAccess) -> Responses<Vec<DiscoveredBridge>> {
+    match pair::discover().await {
+        Ok(bridges) => Responses::good(bridges),
+        Err(e) => Responses::bad(format!("{e:#}")),
+    }
+}
+
+/// Asks a bridge for an API key and saves it to the data dir. Press the bridge's link button first.
+/// The new credentials are used after a restart, unless HUE_BRIDGE_BASE_URL is set.
+#[utoipa::path(
+    request_body(content = Option<PairRequest>, description = "Everything is looked up when missing."),
+    responses(
+        (status = 200, description = "The bridge's new credentials.", body = PairResponse),
+        (status = 400, description = "No bridge answered on mDNS or several did, the link button wasn't pressed, or the bridge couldn't be reached.", body = ErrorBody),
+    ),
+    security(("bearer" = ["admin"])),
+)]
+#[post("/bridge/pair", data = "<request>")]
+async fn bridge_pair(
+    _access: AdminAccess,
+    request: Option<Json<PairRequest>>,
+    caller: Caller,
+    pairing: &State<Pairing>,
+    homes: &State<Homes>,
+    audit: &State<AuditLog>,
+) -> Responses<PairResponse> {
+    let request = request.map(Json::into_inner).unwrap_or_default();
+    let response = match pairing.pair(request).await {
+        Ok(response) => response,
+        Err(e) => return Responses::bad(format!("{e:#}")),
+    };
+
+    let now = homes.default_home().schedule.lock().await.now();
+    audit.record(AuditEntry::new(now, AuditEvent::Pair, &caller).with_detail(response.bridge.base_url.clone()));
+    Responses::good(response)
+}
+
+/// The most recent webhook deliveries, newest first, including ones still being retried.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Recent deliveries, newest first.", body = [Delivery]),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/webhooks/deliveries")]
+fn webhook_deliveries(_access: ReadAccess, webhooks: &State<Webhooks>) -> Json<Vec<Delivery>> {
+    Json(webhooks.deliveries())
+}
+
+/// Prometheus metrics in the text format, with a `home` label on everything about a home's schedule. Like `/healthz`,
+/// this never needs a token, so Prometheus can scrape it without one. It only shows the blended values and counts,
+/// never overrides, tokens or the schedule itself.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format.", content_type = "text/plain", body = String),
+        (status = 500, description = "The metrics couldn't be encoded."),
+    ),
+)]
+#[get("/metrics")]
+#[tracing::instrument(parent = &span.0, skip_all)]
+async fn get_metrics(
+    span: RequestSpan,
+    homes: &State<Homes>,
+    metrics: &State<Metrics>,
+    log: ActionLog<'_>,
+) -> Result<String, Status> {
+    let mut snapshots = Vec::new();
+    for home in homes.iter() {
+        let (progress, reloads, now) = {
+            let mut schedule = home.schedule.lock().await;
+            let now = schedule.now();
+            (schedule.progress(&now), schedule.reload_counts(), now)
+        };
+        if let (DEFAULT_HOME, Ok(progress)) = (home.id.as_str(), &progress) {
+            log.record(DEFAULT_HOME, Some(progress.segment_index), &progress.change_action);
+        }
+        snapshots.push(HomeSnapshot { home: home.id.clone(), progress, reloads, sink_failures: home.statuses.failure_counts(), now });
+    }
+    metrics.render(&snapshots).map_err(|e| {
+        error!("Unable to render metrics: {e:#}");
+        Status::InternalServerError
+    })
+}
+
+fn parse_time(name: &str, value: Option<&str>) -> Result<Option<DateTime<FixedOffset>>, String> {
+    value
+        .map(|s| DateTime::parse_from_rfc3339(s).map_err(|e| format!("`{name}` must be an RFC 3339 timestamp ({s}): {e}")))
+        .transpose()
+}
+
+/// Everything recorded in the audit log between `from` and `to`, which are RFC 3339 timestamps.
+/// Remember to escape `+` offsets as `%2B`, or use `Z`.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "Audit log entries, oldest first.", body = [AuditEntry]),
+        (status = 400, description = "`from` or `to` isn't an RFC 3339 timestamp, or the audit log couldn't be read.", body = ErrorBody),
+    ),
+    security(("bearer" = ["read"])),
+)]
+#[get("/history?<from>&<to>")]
+async fn get_history(_access: ReadAccess, from: Option<&str>, to: Option<&str>, audit: &State<AuditLog>) -> Responses<Vec<AuditEntry>> {
+    let (from, to) = match (parse_time("from", from), parse_time("to", to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return Responses::bad(e),
+    };
+
+    match audit.query(from, to) {
+        Ok(entries) => Responses::good(entries),
+        Err(e) => Responses::bad(format!("{e:#}")),
+    }
 }
 
 #[catch(404)]
@@ -110,16 +914,83 @@ fn not_found_handler(_req: &rocket::Request) -> String {
     String::from("{\"error:\": \"In Rust, not found.\"}")
 }
 
+#[catch(401)]
+fn unauthorized_handler(req: &rocket::Request) -> Responses<()> {
+    Responses::unauthorized(auth::failure_message(req, Status::Unauthorized))
+}
+
+#[catch(403)]
+fn forbidden_handler(req: &rocket::Request) -> Responses<()> {
+    Responses::forbidden(auth::failure_message(req, Status::Forbidden))
+}
+
+/// The routes that read or change one home's schedule. They're mounted at `/` for the default home, and under
+/// `/homes/<id>` for every home.
+fn home_routes() -> Vec<rocket::Route> {
+    routes![
+        now, now_v2, get_debug_info, stream, control_channel, force_update,
+        list_overrides, add_override, cancel_all_overrides, cancel_override,
+        pause_schedule, resume_schedule, get_schedule, upload_schedule,
+        get_schedule_history, rollback_schedule,
+    ]
+}
+
 #[launch]
 fn rocket() -> _ {
-    match dotenvy::dotenv() {
+    let dotenv = dotenvy::dotenv();
+    let log_format = logging::init_from_env("LOG_FORMAT", "LOG_LEVEL");
+    let telemetry = Telemetry::init_from_env("OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_SERVICE_NAME", "TRACE_FILE").unwrap();
+    match dotenv {
         Err(e) => info!(".env NOT LOADED: {} (This could be intentional.)", e),
         Ok(_) => info!("Successfully loaded .env"),
     };
 
-    rocket::build()
-        .attach(fairing::AutoLogger)
-        .manage(Arc::new(Mutex::new(Schedule::new().unwrap())))
-        .mount("/", routes![index, get_debug_info, now, force_update])
-        .register("/", catchers![not_found_handler])
+    let mut figment = rocket::Config::figment();
+    if log_format == LogFormat::Json {
+        // Escape codes would end up inside the JSON strings.
+        figment = figment.merge(("cli_colors", false));
+    }
+    // Installs Rocket's text logger, so the warnings below aren't lost.
+    let rocket = rocket::custom(figment);
+
+    let schedule = Schedule::new().unwrap();
+    let audit = AuditLog::from_env("DATA_DIR", "AUDIT_LOG_MAX_BYTES", "AUDIT_LOG_FILES");
+    audit.record(AuditEntry::new(schedule.now(), AuditEvent::Reload, &Caller::internal("startup"))
+        .with_detail(ReloadSource::Startup.to_string()));
+    let pairing = Pairing::from_env("DATA_DIR", "HUE_BRIDGE_CACERT_PEM_PATH");
+    let hue = HueClient::from_env("HUE_BRIDGE_BASE_URL", "HUE_BRIDGE_API_KEY", "HUE_BRIDGE_CACERT_PEM_PATH", "HUE_BRIDGE_ID", &pairing).unwrap();
+    let default = Home::new(DEFAULT_HOME, schedule, ScheduleEvents::from_env("STREAM_POLL_SECONDS"), hue.clone());
+    let homes = Homes::from_env("HOMES", default).unwrap();
+    let api_tokens = ApiTokens::from_env("API_TOKENS", "API_TOKENS_FILE", "API_ANONYMOUS_READ", "API_AUTH_DISABLED").unwrap();
+
+    let mut rocket = rocket
+        .attach(auth::QueryTokens)
+        .attach(fairing::AutoLogger::from_env("LOG_BODIES", "LOG_BODY_MAX_BYTES", "LOG_REDACT_FIELDS"))
+        .attach(metrics::RequestMetrics)
+        .attach(telemetry)
+        .attach(reload::ScheduleReloader)
+        .attach(apply::ApplyLoop)
+        .attach(webhooks::WebhookNotifier)
+        .attach(events::StreamPublisher)
+        .manage(audit)
+        .manage(SinkReadiness::from_env("READY_SINK_FAILURES"))
+        .manage(Metrics::new().unwrap())
+        .manage(Webhooks::new().unwrap())
+        .manage(hue)
+        .manage(pairing)
+        .manage(api_tokens)
+        .mount("/", routes![
+            index, healthz, readyz, get_history, list_homes,
+            bridge_lights, bridge_rooms, bridge_zones, bridge_grouped_lights, bridge_update,
+            bridge_discover, bridge_pair, webhook_deliveries, get_metrics,
+        ])
+        .mount("/", home_routes());
+    for home in homes.iter() {
+        rocket = rocket.mount(home.base(), home_routes());
+    }
+
+    rocket
+        .manage(homes)
+        .mount("/", RapiDoc::with_url("/docs", "/openapi.json", ApiDoc::openapi()))
+        .register("/", catchers![not_found_handler, unauthorized_handler, forbidden_handler])
 }
@@ -0,0 +1,232 @@
+//! Prometheus metrics about requests and where the schedule is, for `/metrics`.
+
+use std::{collections::BTreeMap, time::Instant};
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    tokio::time::error::Elapsed,
+    Data, Request, Response,
+};
+
+use crate::{
+    fairing::{route_name, RequestStart},
+    reload::ReloadCounts,
+    schedule::{ChangeAction, ScheduleProgress},
+};
+
+const NAMESPACE: &str = "hue_blend";
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_seconds: HistogramVec,
+    errors: IntCounterVec,
+    reloads: IntCounterVec,
+    mirek: GaugeVec,
+    brightness: GaugeVec,
+    segment_index: IntGaugeVec,
+    seconds_to_next_item: GaugeVec,
+    sunset_timestamp: IntGaugeVec,
+}
+
+/// One home's state, for `Metrics::render`.
+pub struct HomeSnapshot {
+    pub home: String,
+    pub progress: anyhow::Result<ScheduleProgress>,
+    pub reloads: ReloadCounts,
+    /// Sink failures since startup, by `error_kind`.
+    pub sink_failures: BTreeMap<&'static str, u64>,
+    pub now: DateTime<Tz>,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(NAMESPACE);
+        let metrics = Metrics {
+            registry: Registry::new(),
+            requests: IntCounterVec::new(opts("http_requests_total", "HTTP requests by route and status."), &["method", "route", "status"])?,
+            request_seconds: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "How long HTTP requests took to answer.").namespace(NAMESPACE),
+                &["method", "route"],
+            )?,
+            errors: IntCounterVec::new(
+                opts("errors_total", "Failures to work out a home's schedule or deliver it to a sink, by kind."),
+                &["home", "kind"],
+            )?,
+            reloads: IntCounterVec::new(opts("schedule_reloads_total", "Schedule reloads by whether they were applied."), &["home", "result"])?,
+            mirek: GaugeVec::new(opts("mirek", "The blended color temperature. NaN while the lights are left alone."), &["home"])?,
+            brightness: GaugeVec::new(opts("brightness", "The blended brightness. NaN while the lights are left alone."), &["home"])?,
+            segment_index: IntGaugeVec::new(opts("segment_index", "Which of today's items was passed most recently."), &["home"])?,
+            seconds_to_next_item: GaugeVec::new(opts("seconds_to_next_item", "Seconds until the next item's time."), &["home"])?,
+            sunset_timestamp: IntGaugeVec::new(opts("sunset_timestamp_seconds", "Today's sunset, in Unix time."), &["home"])?,
+        };
+
+        metrics.registry.register(Box::new(metrics.requests.clone()))?;
+        metrics.registry.register(Box::new(metrics.request_seconds.clone()))?;
+        metrics.registry.register(Box::new(metrics.errors.clone()))?;
+        metrics.registry.register(Box::new(metrics.reloads.clone()))?;
+        metrics.registry.register(Box::new(metrics.mirek.clone()))?;
+        metrics.registry.register(Box::new(metrics.brightness.clone()))?;
+        metrics.registry.register(Box::new(metrics.segment_index.clone()))?;
+        metrics.registry.register(Box::new(metrics.seconds_to_next_item.clone()))?;
+        metrics.registry.register(Box::new(metrics.sunset_timestamp.clone()))?;
+        Ok(metrics)
+    }
+
+    fn observe_request(&self, method: &str, route: &str, status: u16, seconds: f64) {
+        self.requests.with_label_values(&[method, route, &status.to_string()]).inc();
+        self.request_seconds.with_label_values(&[method, route]).observe(seconds);
+    }
+
+    pub fn count_error(&self, home: &str, kind: &str) {
+        self.errors.with_label_values(&[home, kind]).inc();
+    }
+
+    /// Brings every home's gauges up to date, then encodes everything in the text format. A home's gauges keep their
+    /// last values when its `progress` failed; whoever hit the failure counts it.
+    pub fn render(&self, homes: &[HomeSnapshot]) -> anyhow::Result<String> {
+        for snapshot in homes {
+            let home = snapshot.home.as_str();
+            if let Ok(progress) = &snapshot.progress {
+                let (mirek, brightness) = match progress.change_action {
+                    ChangeAction::Color { mirek, brightness } => (mirek.into(), brightness.into()),
+                    ChangeAction::None => (f64::NAN, f64::NAN),
+                };
+                self.mirek.with_label_values(&[home]).set(mirek);
+                self.brightness.with_label_values(&[home]).set(brightness);
+                self.segment_index.with_label_values(&[home]).set(progress.segment_index as i64);
+                self.seconds_to_next_item.with_label_values(&[home])
+                    .set((progress.next_item_at - snapshot.now).num_milliseconds() as f64 / 1000.);
+                self.sunset_timestamp.with_label_values(&[home]).set(progress.sunset_at.timestamp());
+            }
+            // Counters can't be set, but the schedule's counts only go up too.
+            sync_counter(&self.reloads.with_label_values(&[home, "success"]), snapshot.reloads.succeeded);
+            sync_counter(&self.reloads.with_label_values(&[home, "failure"]), snapshot.reloads.failed);
+            for (kind, count) in &snapshot.sink_failures {
+                sync_counter(&self.errors.with_label_values(&[home, kind]), *count);
+            }
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+fn sync_counter(counter: &IntCounter, count: u64) {
+    counter.inc_by(count.saturating_sub(counter.get()));
+}
+
+/// What kind of failure `error` was, for the `kind` label of `errors_total`.
+pub fn error_kind(error: &anyhow::Error) -> &'static str {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return match () {
+                _ if e.is_timeout() => "timeout",
+                _ if e.is_connect() => "connect",
+                _ if e.is_decode() => "invalid_response",
+                _ => "request",
+            };
+        }
+        if cause.is::<Elapsed>() {
+            return "timeout";
+        }
+        if cause.is::<rumqttc::ClientError>() {
+            return "queue_full";
+        }
+    }
+    // Something answered, such as the bridge or a webhook receiver, but with an error.
+    "rejected"
+}
+
+/// Counts and times every request. Error responses show up by their status.
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info { name: "Request metrics", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(metrics) = request.rocket().state::<Metrics>() else { return };
+        let seconds = request.local_cache(|| RequestStart(Instant::now())).0.elapsed().as_secs_f64();
+        let route = route_name(request);
+        let status = response.status();
+        metrics.observe_request(request.method().as_str(), route, status.code, seconds);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+    use chrono::TimeZone;
+    use chrono_tz::US::Eastern;
+    use rocket::tokio::time::{sleep, timeout};
+    use crate::{reload::ReloadCounts, schedule::{ChangeAction, ScheduleProgress}};
+    use super::{error_kind, HomeSnapshot, Metrics};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new().unwrap();
+        let now = Eastern.with_ymd_and_hms(1999, 1, 1, 18, 0, 0).unwrap();
+        let progress = ScheduleProgress {
+            change_action: ChangeAction::Color { mirek: 370, brightness: 40 },
+            segment_index: 2,
+            next_item_at: Eastern.with_ymd_and_hms(1999, 1, 1, 18, 30, 0).unwrap(),
+            sunset_at: Eastern.with_ymd_and_hms(1999, 1, 1, 16, 40, 0).unwrap(),
+        };
+        let snapshot = |home: &str, progress, succeeded, timeouts| HomeSnapshot {
+            home: home.to_string(),
+            progress,
+            reloads: ReloadCounts { succeeded, failed: 1 },
+            sink_failures: BTreeMap::from([("timeout", timeouts)]),
+            now,
+        };
+        metrics.observe_request("GET", "/now", 200, 0.01);
+        metrics.observe_request("GET", "/now", 400, 0.01);
+
+        let cabin = ScheduleProgress { change_action: ChangeAction::None, ..progress.clone() };
+        let text = metrics.render(&[snapshot("default", Ok(progress), 2, 2), snapshot("cabin", Ok(cabin), 0, 0)]).unwrap();
+        for line in [
+            "hue_blend_mirek{home=\"default\"} 370",
+            "hue_blend_mirek{home=\"cabin\"} NaN",
+            "hue_blend_brightness{home=\"default\"} 40",
+            "hue_blend_segment_index{home=\"default\"} 2",
+            "hue_blend_seconds_to_next_item{home=\"default\"} 1800",
+            "hue_blend_sunset_timestamp_seconds{home=\"default\"} 915226800",
+            "hue_blend_http_requests_total{method=\"GET\",route=\"/now\",status=\"400\"} 1",
+            "hue_blend_http_request_duration_seconds_count{method=\"GET\",route=\"/now\"} 2",
+            "hue_blend_errors_total{home=\"default\",kind=\"timeout\"} 2",
+            "hue_blend_schedule_reloads_total{home=\"default\",result=\"success\"} 2",
+            "hue_blend_schedule_reloads_total{home=\"cabin\",result=\"failure\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} missing from:\n{text}");
+        }
+
+        let text = metrics.render(&[snapshot("default", Err(anyhow::anyhow!("no schedule")), 3, 3)]).unwrap();
+        assert!(text.lines().any(|l| l == "hue_blend_mirek{home=\"default\"} 370"));
+        assert!(!text.contains("kind=\"schedule\""));
+        assert!(text.contains("hue_blend_errors_total{home=\"default\",kind=\"timeout\"} 3"));
+        assert!(text.contains("hue_blend_schedule_reloads_total{home=\"default\",result=\"success\"} 3"));
+    }
+
+    #[rocket::async_test]
+    async fn test_error_kind() {
+        let refused = reqwest::get("http://127.0.0.1:1/").await.unwrap_err();
+        assert_eq!(error_kind(&anyhow::Error::new(refused).context("POST failed")), "connect");
+        let elapsed = timeout(Duration::from_millis(1), sleep(Duration::from_secs(10))).await.unwrap_err();
+        assert_eq!(error_kind(&anyhow::Error::new(elapsed)), "timeout");
+        assert_eq!(error_kind(&anyhow::anyhow!("PUT light/1 returned 404 Not Found")), "rejected");
+    }
+}
@@ -0,0 +1,171 @@
+//! A bare-bones HTTP server that stands in for a Hue bridge in tests.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use rocket::{
+    serde::json::{json, serde_json, Value},
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+};
+
+use crate::hue::{HueClient, HueConfig};
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+#[derive(Clone)]
+struct MockResponse {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+#[derive(Default)]
+struct MockState {
+    responses: HashMap<(String, String), MockResponse>,
+    requests: Vec<RecordedRequest>,
+}
+
+pub struct MockBridge {
+    port: u16,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockBridge {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(MockState::default()));
+
+        let accept_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle(stream, accept_state.clone()));
+            }
+        });
+
+        MockBridge { port, state }
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://127.0.0.1:{}/", self.port)
+    }
+
+    pub fn client(&self) -> HueClient {
+        HueClient::new(&HueConfig {
+            base_url: self.base_url(),
+            api_key: String::from("test-key"),
+            ca_cert_pem_path: None,
+            bridge_id: None,
+        }).unwrap()
+    }
+
+    /// Anything without a response gets a 404 with an empty body.
+    pub fn respond(&self, method: &str, path: &str, status: u16, body: Value) {
+        let response = MockResponse { status, content_type: "application/json", body: body.to_string() };
+        self.state.lock().unwrap().responses.insert((method.to_string(), path.to_string()), response);
+    }
+
+    /// Serves `events` as a server-sent event stream, which ends once they've been sent.
+    pub fn respond_stream(&self, path: &str, events: String) {
+        let response = MockResponse { status: 200, content_type: "text/event-stream", body: events };
+        self.state.lock().unwrap().responses.insert((String::from("GET"), path.to_string()), response);
+    }
+
+    /// A room called Living room with one light, Lamp, which accepts updates to its grouped_light.
+    pub fn respond_living_room(&self) {
+        self.respond("GET", "/clip/v2/resource/zone", 200, json!({ "errors": [], "data": [] }));
+        self.respond("GET", "/clip/v2/resource/room", 200, json!({
+            "errors": [],
+            "data": [{
+                "id": "room-1",
+                "children": [{ "rid": "device-1", "rtype": "device" }],
+                "services": [{ "rid": "grouped-1", "rtype": "grouped_light" }],
+                "metadata": { "name": "Living room", "archetype": "living_room" },
+            }],
+        }));
+        self.respond("GET", "/clip/v2/resource/light", 200, json!({
+            "errors": [],
+            "data": [{
+                "id": "light-1",
+                "owner": { "rid": "device-1", "rtype": "device" },
+                "metadata": { "name": "Lamp" },
+                "on": { "on": true },
+            }],
+        }));
+        self.respond("PUT", "/clip/v2/resource/grouped_light/grouped-1", 200, json!({ "errors": [], "data": [] }));
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+async fn handle(mut stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let mut buffer = Vec::new();
+    let header_end = loop {
+        let mut chunk = [0; 4096];
+        let Ok(read @ 1..) = stream.read(&mut chunk).await else { return };
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = buffer[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let mut chunk = [0; 4096];
+        let Ok(read @ 1..) = stream.read(&mut chunk).await else { return };
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        let response = state.responses.get(&(method.clone(), path.clone())).cloned();
+        state.requests.push(RecordedRequest { method, path, headers, body: String::from_utf8_lossy(&body).to_string() });
+        response.unwrap_or(MockResponse { status: 404, content_type: "application/json", body: String::new() })
+    };
+
+    let response = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status, response.content_type, response.body.len(), response.body,
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
@@ -0,0 +1,80 @@
+//! A bare-bones MQTT broker that accepts every client and records what they publish, for tests.
+
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+use rocket::tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use rumqttc::{ConnAck, ConnectReturnCode, Packet, PingResp, PubAck, Publish, QoS};
+
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+pub struct MockBroker {
+    port: u16,
+    published: Arc<Mutex<Vec<Publish>>>,
+}
+
+impl MockBroker {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let published = Arc::new(Mutex::new(Vec::new()));
+
+        let accept_published = published.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle(stream, accept_published.clone()));
+            }
+        });
+
+        MockBroker { port, published }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn published(&self) -> Vec<Publish> {
+        self.published.lock().unwrap().clone()
+    }
+}
+
+async fn handle(mut stream: TcpStream, published: Arc<Mutex<Vec<Publish>>>) {
+    let mut buffer = BytesMut::new();
+    loop {
+        let packet = match rumqttc::read(&mut buffer, MAX_PACKET_SIZE) {
+            Ok(packet) => packet,
+            Err(rumqttc::Error::InsufficientBytes(_)) => {
+                let mut chunk = [0; 4096];
+                let Ok(read @ 1..) = stream.read(&mut chunk).await else { return };
+                buffer.extend_from_slice(&chunk[..read]);
+                continue;
+            },
+            Err(_) => return,
+        };
+
+        let mut response = BytesMut::new();
+        match packet {
+            Packet::Connect(_) => {
+                ConnAck::new(ConnectReturnCode::Success, false).write(&mut response).unwrap();
+            },
+            Packet::Publish(publish) => {
+                if publish.qos == QoS::AtLeastOnce {
+                    PubAck::new(publish.pkid).write(&mut response).unwrap();
+                }
+                published.lock().unwrap().push(publish);
+            },
+            Packet::PingReq => {
+                PingResp.write(&mut response).unwrap();
+            },
+            Packet::Disconnect => return,
+            _ => {},
+        }
+        if !response.is_empty() && stream.write_all(&response).await.is_err() {
+            return;
+        }
+    }
+}
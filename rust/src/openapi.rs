@@ -0,0 +1,121 @@
+//! The OpenAPI document for `/openapi.json`, generated from the routes and the types they send and receive.
+
+use utoipa::{
+    openapi::{
+        path::{ParameterBuilder, ParameterIn},
+        security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+        ComponentsBuilder, ObjectBuilder, OpenApi as OpenApiDoc, Ref, Required, ResponseBuilder, Type,
+    },
+    Modify, OpenApi,
+};
+
+const BEARER: &str = "bearer";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "Blends Hue lights' color temperature and brightness through the day."),
+    paths(
+        crate::index, crate::healthz, crate::readyz, crate::get_debug_info, crate::now, crate::now_v2,
+        crate::stream, crate::control_channel, crate::force_update,
+        crate::list_overrides, crate::add_override, crate::cancel_all_overrides, crate::cancel_override,
+        crate::pause_schedule, crate::resume_schedule, crate::get_schedule, crate::upload_schedule,
+        crate::get_schedule_history, crate::rollback_schedule, crate::get_history,
+        crate::list_homes,
+        crate::bridge_lights, crate::bridge_rooms, crate::bridge_zones, crate::bridge_grouped_lights, crate::bridge_update,
+        crate::bridge_discover, crate::bridge_pair, crate::webhook_deliveries, crate::get_metrics,
+    ),
+    modifiers(&HomePaths, &BearerAuth),
+)]
+pub struct ApiDoc;
+
+/// Documents each of `crate::home_routes` a second time under `/homes/{home}`, where it acts on that home.
+struct HomePaths;
+
+impl Modify for HomePaths {
+    fn modify(&self, openapi: &mut OpenApiDoc) {
+        let home = ParameterBuilder::new()
+            .name("home")
+            .parameter_in(ParameterIn::Path)
+            .required(Required::True)
+            .description(Some("A home's id, from `/homes`."))
+            .schema(Some(ObjectBuilder::new().schema_type(Type::String)))
+            .build();
+
+        for route in crate::home_routes() {
+            let path = route.uri.path().replace('<', "{").replace('>', "}");
+            let Some(mut item) = openapi.paths.paths.get(&path).cloned() else { continue };
+            item.parameters = Some(vec![home.clone()]);
+            let operations = [&mut item.get, &mut item.put, &mut item.post, &mut item.delete];
+            for operation in operations.into_iter().flatten() {
+                operation.operation_id = operation.operation_id.take().map(|id| format!("home_{id}"));
+            }
+            openapi.paths.paths.insert(format!("/homes/{{home}}{path}"), item);
+        }
+    }
+}
+
+/// Declares the bearer token scheme, and the 401 and 403 responses every route that needs a token can give.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut OpenApiDoc) {
+        let components = openapi.components.get_or_insert_with(|| ComponentsBuilder::new().build());
+        components.add_security_scheme(BEARER, SecurityScheme::Http(HttpBuilder::new()
+            .scheme(HttpAuthScheme::Bearer)
+            .description(Some("A token from API_TOKENS or API_TOKENS_FILE. Scopes nest: admin includes override, \
+                which includes read. Routes that only need read may be open, if API_ANONYMOUS_READ is set, and every route \
+                is open if API_AUTH_DISABLED is set. /stream and /ws also take the token as an access_token query parameter."))
+            .build()));
+
+        let error = |description: &str| ResponseBuilder::new()
+            .description(description)
+            .content("application/json", utoipa::openapi::Content::new(Some(Ref::from_schema_name("ErrorBody"))))
+            .build();
+        for item in openapi.paths.paths.values_mut() {
+            let operations = [&mut item.get, &mut item.put, &mut item.post, &mut item.delete];
+            for operation in operations.into_iter().flatten().filter(|operation| operation.security.is_some()) {
+                operation.responses.responses.insert(String::from("401"), error("The token is missing or unknown.").into());
+                operation.responses.responses.insert(String::from("403"), error("The token doesn't have the scope this needs.").into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utoipa::OpenApi;
+    use super::ApiDoc;
+
+    #[test]
+    fn test_openapi() {
+        let json = ApiDoc::openapi().to_json().unwrap();
+        let json: rocket::serde::json::Value = rocket::serde::json::serde_json::from_str(&json).unwrap();
+
+        let now = &json["paths"]["/now"]["get"];
+        assert_eq!(now["responses"]["200"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/NowResponse");
+        assert_eq!(now["responses"]["401"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/ErrorBody");
+        assert_eq!(now["security"][0]["bearer"][0], "read");
+        assert_eq!(
+            json["paths"]["/v2/now"]["get"]["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/v2.Now",
+        );
+        assert_eq!(json["components"]["schemas"]["v2.Action"]["required"], rocket::serde::json::json!(["type", "mirek", "brightness"]));
+        assert!(json["paths"]["/healthz"]["get"]["responses"]["401"].is_null());
+        assert!(json["paths"]["/metrics"]["get"]["responses"]["401"].is_null());
+        assert_eq!(json["paths"]["/schedule"]["put"]["parameters"][0]["name"], "dry_run");
+        assert_eq!(json["paths"]["/override/{id}"]["delete"]["parameters"][0]["in"], "path");
+
+        let home_override = &json["paths"]["/homes/{home}/override/{id}"];
+        assert_eq!(home_override["parameters"][0]["name"], "home");
+        assert_eq!(home_override["delete"]["operationId"], "home_cancel_override");
+        assert_eq!(home_override["delete"]["responses"]["401"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/ErrorBody");
+        assert!(json["paths"]["/homes/{home}/stream"]["get"].is_object());
+        assert!(json["paths"]["/homes/{home}/bridge/lights"].is_null());
+
+        let schemas = &json["components"]["schemas"];
+        for schema in ["ChangeAction", "ScheduleYamlConfig", "Override", "Light", "AuditEntry", "Delivery"] {
+            assert!(schemas[schema].is_object(), "{schema} missing");
+        }
+        assert_eq!(json["components"]["securitySchemes"]["bearer"]["scheme"], "bearer");
+    }
+}
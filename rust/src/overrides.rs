@@ -0,0 +1,191 @@
+use chrono::{DateTime, TimeDelta};
+use chrono_tz::Tz;
+use rocket::serde;
+
+use crate::{schedule::{fraction, ChangeAction}, time::deserialize_utc};
+
+/// When a manual override stops taking precedence over the schedule.
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Expiry {
+	ForMinutes(u32),
+	UntilNextItem,
+	UntilSunrise,
+	/// Ended by the apply loop once the lights are switched off and back on. In case that never happens, it
+	/// expires on its own after the schedule's `overrides.lights_cycled_max_hours`, 12 unless configured.
+	UntilLightsCycled,
+}
+
+/// What added an override.
+#[derive(Debug, Default, PartialEq, Clone, Copy, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum OverrideSource {
+	#[default]
+	Api,
+	/// The apply loop, holding off after the lights were changed with the Hue app or a switch.
+	ManualChange,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct OverrideRequest {
+	pub change_action: ChangeAction,
+	/// `until_lights_cycled` also expires after the schedule's `overrides.lights_cycled_max_hours` (12 unless
+	/// configured) if the lights are never switched off and on.
+	pub expiry: Expiry,
+	/// How long to blend back into the schedule after expiring. Falls back to the configured default.
+	pub fade_minutes: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Override {
+	pub id: u64,
+	pub change_action: ChangeAction,
+	pub expiry: Expiry,
+	#[serde(deserialize_with = "deserialize_utc")]
+	pub created: DateTime<Tz>,
+	#[serde(deserialize_with = "deserialize_utc")]
+	pub expires: DateTime<Tz>,
+	#[serde(deserialize_with = "deserialize_utc")]
+	pub fade_end: DateTime<Tz>,
+	/// Overrides saved before this was added came from the API.
+	#[serde(default)]
+	pub source: OverrideSource,
+}
+
+impl Override {
+	pub fn new(id: u64, request: &OverrideRequest, now: DateTime<Tz>, expires: DateTime<Tz>, fade: TimeDelta) -> anyhow::Result<Self> {
+		if let ChangeAction::Color { brightness, .. } = request.change_action {
+			if brightness > 100 {
+				return Err(anyhow::anyhow!("brightness ({brightness}) must be between 0 and 100."));
+			}
+		}
+		if expires <= now {
+			return Err(anyhow::anyhow!("Override would expire at {expires}, which is not after now ({now})."));
+		}
+
+		Ok(Override {
+			id,
+			change_action: request.change_action.clone(),
+			expiry: request.expiry.clone(),
+			created: now,
+			expires,
+			fade_end: expires + fade,
+			source: OverrideSource::Api,
+		})
+	}
+
+	pub fn is_finished(&self, now: &DateTime<Tz>) -> bool {
+		&self.fade_end <= now
+	}
+
+	/// Combines this override with what the schedule wants at `now`.
+	fn apply(&self, now: &DateTime<Tz>, scheduled: ChangeAction) -> ChangeAction {
+		if now < &self.expires {
+			return self.change_action.clone();
+		}
+
+		let total = (self.fade_end - self.expires).num_milliseconds();
+		if total <= 0 {
+			return scheduled;
+		}
+		let progress = (*now - self.expires).num_milliseconds() as f64 / total as f64;
+
+		fade(&self.change_action, scheduled, progress.clamp(0., 1.))
+	}
+}
+
+/// Blends `from` into `to`, where a `progress` of 0 is all `from` and 1 is all `to`.
+fn fade(from: &ChangeAction, to: ChangeAction, progress: f64) -> ChangeAction {
+	match (from, &to) {
+		(
+			ChangeAction::Color { mirek: from_mirek, brightness: from_brightness },
+			ChangeAction::Color { mirek: to_mirek, brightness: to_brightness },
+		) => ChangeAction::Color {
+			mirek: fraction(1. - progress, *from_mirek, progress, *to_mirek) as u16,
+			brightness: fraction(1. - progress, *from_brightness, progress, *to_brightness) as u8,
+		},
+		// There's nothing to blend with when either side leaves the lights alone.
+		_ => to,
+	}
+}
+
+/// The most recently created override that still affects `now`.
+pub fn active_override<'a>(overrides: &'a [Override], now: &DateTime<Tz>) -> Option<&'a Override> {
+	overrides
+		.iter()
+		.filter(|o| &o.created <= now && !o.is_finished(now))
+		.max_by_key(|o| o.id)
+}
+
+pub fn apply_overrides(overrides: &[Override], now: &DateTime<Tz>, scheduled: ChangeAction) -> ChangeAction {
+	match active_override(overrides, now) {
+		Some(o) => o.apply(now, scheduled),
+		None => scheduled,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::{TimeDelta, TimeZone};
+	use chrono_tz::{Tz, US::Eastern};
+	use crate::schedule::ChangeAction;
+	use super::{active_override, apply_overrides, Expiry, Override, OverrideRequest};
+
+	fn hm(hour: u32, minute: u32) -> chrono::DateTime<Tz> {
+		Eastern.with_ymd_and_hms(1999, 1, 1, hour, minute, 0).unwrap()
+	}
+
+	fn color(mirek: u16, brightness: u8) -> ChangeAction {
+		ChangeAction::Color { mirek, brightness }
+	}
+
+	fn create_override(id: u64, change_action: ChangeAction, created: (u32, u32), expires: (u32, u32), fade_minutes: i64) -> Override {
+		let request = OverrideRequest { change_action, expiry: Expiry::ForMinutes(0), fade_minutes: None };
+		Override::new(id, &request, hm(created.0, created.1), hm(expires.0, expires.1), TimeDelta::minutes(fade_minutes))
+			.expect("Expected override to be valid.")
+	}
+
+	#[test]
+	fn test_override_fades_back_into_schedule() {
+		let overrides = vec![create_override(1, color(200, 100), (10, 0), (12, 0), 10)];
+		let scheduled = color(400, 0);
+
+		assert_eq!(apply_overrides(&overrides, &hm(9, 59), scheduled.clone()), scheduled);
+		assert_eq!(apply_overrides(&overrides, &hm(10, 0), scheduled.clone()), color(200, 100));
+		assert_eq!(apply_overrides(&overrides, &hm(11, 59), scheduled.clone()), color(200, 100));
+		assert_eq!(apply_overrides(&overrides, &hm(12, 5), scheduled.clone()), color(300, 50));
+		assert_eq!(apply_overrides(&overrides, &hm(12, 10), scheduled.clone()), scheduled);
+	}
+
+	#[test]
+	fn test_override_none_does_not_fade() {
+		let overrides = vec![create_override(1, ChangeAction::None, (10, 0), (12, 0), 10)];
+
+		assert_eq!(apply_overrides(&overrides, &hm(11, 0), color(400, 0)), ChangeAction::None);
+		assert_eq!(apply_overrides(&overrides, &hm(12, 1), color(400, 0)), color(400, 0));
+	}
+
+	#[test]
+	fn test_latest_override_wins() {
+		let overrides = vec![
+			create_override(1, color(200, 100), (10, 0), (14, 0), 0),
+			create_override(2, color(300, 50), (11, 0), (12, 0), 0),
+		];
+
+		assert_eq!(active_override(&overrides, &hm(10, 30)).map(|o| o.id), Some(1));
+		assert_eq!(active_override(&overrides, &hm(11, 30)).map(|o| o.id), Some(2));
+		assert_eq!(active_override(&overrides, &hm(12, 30)).map(|o| o.id), Some(1));
+		assert_eq!(active_override(&overrides, &hm(14, 0)), None);
+	}
+
+	#[test]
+	fn test_invalid_override() {
+		let request = OverrideRequest { change_action: color(300, 101), expiry: Expiry::UntilNextItem, fade_minutes: None };
+		assert!(Override::new(1, &request, hm(10, 0), hm(11, 0), TimeDelta::zero()).is_err());
+
+		let request = OverrideRequest { change_action: color(300, 100), expiry: Expiry::UntilNextItem, fade_minutes: None };
+		assert!(Override::new(1, &request, hm(10, 0), hm(10, 0), TimeDelta::zero()).is_err());
+	}
+}
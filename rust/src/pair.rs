@@ -0,0 +1,250 @@
+//! Finding a bridge on the local network and asking it for an application key.
+
+use std::{collections::HashMap, env, fs, net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, Context};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use rocket::{
+    serde::{self, json::{json, serde_json}},
+    tokio::{task::spawn_blocking, time::{timeout_at, Instant}},
+};
+
+use crate::{hue::{client_builder, HueConfig}, store::write_atomically};
+
+const HUE_SERVICE_TYPE: &str = "_hue._tcp.local.";
+const DISCOVERY_TIME: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const PAIRED_FILE_NAME: &str = "bridge.json";
+/// How the key is listed under the bridge's connected apps.
+const DEVICE_TYPE: &str = "rust-hue#hue-blend";
+/// What the bridge answers until its link button has been pressed.
+const LINK_BUTTON_NOT_PRESSED: u16 = 101;
+
+#[derive(Debug, PartialEq, Clone, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct DiscoveredBridge {
+    /// From the bridge's TXT record, lowercased to match its certificate.
+    pub id: Option<String>,
+    pub address: String,
+}
+
+/// Bridges that answer on mDNS within a few seconds.
+pub async fn discover() -> anyhow::Result<Vec<DiscoveredBridge>> {
+    let daemon = ServiceDaemon::new().context("Unable to start mDNS")?;
+    let receiver = daemon.browse(HUE_SERVICE_TYPE).context(format!("Unable to browse for {HUE_SERVICE_TYPE}"))?;
+
+    let deadline = Instant::now() + DISCOVERY_TIME;
+    let mut bridges = HashMap::new();
+    while let Ok(Ok(event)) = timeout_at(deadline, receiver.recv_async()).await {
+        let ServiceEvent::ServiceResolved(info) = event else { continue };
+        let addresses = info.get_addresses();
+        let Some(ip) = addresses.iter().find(|ip| ip.is_ipv4()).or(addresses.iter().next()) else { continue };
+        bridges.insert(info.get_fullname().to_string(), DiscoveredBridge {
+            id: info.get_property_val_str("bridgeid").map(str::to_lowercase),
+            address: SocketAddr::new(*ip, info.get_port()).to_string(),
+        });
+    }
+
+    let _ = daemon.shutdown();
+    Ok(bridges.into_values().collect())
+}
+
+#[derive(Debug, Default, serde::Deserialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct PairRequest {
+    /// The bridge's address, e.g. `192.168.1.20`, or its full URL. Found with mDNS when missing.
+    #[serde(default)]
+    pub address: Option<String>,
+    /// Found with mDNS, or asked of the bridge, when missing.
+    #[serde(default)]
+    pub bridge_id: Option<String>,
+}
+
+/// The credentials a bridge handed out, which are used when `HUE_BRIDGE_BASE_URL` isn't set.
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct PairedBridge {
+    pub base_url: String,
+    pub api_key: String,
+    /// Only needed for the entertainment API, but the bridge won't hand it out again.
+    pub client_key: Option<String>,
+    pub bridge_id: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct PairResponse {
+    #[serde(flatten)]
+    pub bridge: PairedBridge,
+    /// Where the credentials were written, if anywhere. They're used after a restart.
+    pub saved_to: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+enum KeyResult {
+    Success { username: String, clientkey: Option<String> },
+    Error {
+        #[serde(rename = "type")]
+        kind: u16,
+        description: String,
+    },
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct BridgeInfo {
+    bridgeid: String,
+}
+
+/// Pairs with bridges and keeps the resulting credentials in the data dir.
+#[derive(Debug, Default)]
+pub struct Pairing {
+    path: Option<PathBuf>,
+    ca_cert_pem_path: Option<String>,
+}
+
+impl Pairing {
+    pub fn from_env(env_data_dir_var: &str, env_ca_cert_var: &str) -> Self {
+        Pairing {
+            path: env::var(env_data_dir_var).ok().map(|dir| PathBuf::from(dir).join(PAIRED_FILE_NAME)),
+            ca_cert_pem_path: env::var(env_ca_cert_var).ok(),
+        }
+    }
+
+    /// Returns `None` if no bridge has been paired.
+    pub fn load(&self) -> anyhow::Result<Option<HueConfig>> {
+        let Some(path) = &self.path else { return Ok(None) };
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let json = fs::read_to_string(path).context(format!("Unable to read {}", path.display()))?;
+        let paired: PairedBridge = serde_json::from_str(&json).context(format!("Unable to parse {}", path.display()))?;
+        Ok(Some(self.config(paired.base_url, paired.api_key, paired.bridge_id)))
+    }
+
+    fn config(&self, base_url: String, api_key: String, bridge_id: Option<String>) -> HueConfig {
+        HueConfig { base_url, api_key, ca_cert_pem_path: self.ca_cert_pem_path.clone(), bridge_id }
+    }
+
+    /// Fails with a reminder to press the link button if it hasn't been pressed in the last 30 seconds.
+    pub async fn pair(&self, request: PairRequest) -> anyhow::Result<PairResponse> {
+        let (address, discovered_id) = match request.address {
+            Some(address) => (address, None),
+            None => {
+                let mut bridges = discover().await?;
+                match bridges.len() {
+                    0 => return Err(anyhow!("No bridges answered on mDNS. Pass their address instead.")),
+                    1 => {
+                        let bridge = bridges.remove(0);
+                        (bridge.address, bridge.id)
+                    },
+                    _ => {
+                        let addresses: Vec<String> = bridges.into_iter().map(|b| b.address).collect();
+                        return Err(anyhow!("Found several bridges ({}). Pass the address of one.", addresses.join(", ")));
+                    },
+                }
+            },
+        };
+
+        let base_url = if address.contains("://") { address } else { format!("https://{address}/") };
+        let mut config = self.config(base_url, String::new(), request.bridge_id.or(discovered_id));
+        if config.bridge_id.is_none() {
+            config.bridge_id = Some(bridge_id(&config).await?);
+        }
+
+        let (builder, url) = client_builder(&config)?;
+        let client = builder.build().context("Unable to create the bridge HTTP client")?;
+        let results: Vec<KeyResult> = client
+            .post(url.join("api")?)
+            .timeout(REQUEST_TIMEOUT)
+            .json(&json!({ "devicetype": DEVICE_TYPE, "generateclientkey": true }))
+            .send()
+            .await
+            .context(format!("Unable to reach the bridge at {}", config.base_url))?
+            .json()
+            .await
+            .context("The bridge returned an unexpected body")?;
+
+        let bridge = match results.into_iter().next() {
+            Some(KeyResult::Success { username, clientkey }) => PairedBridge {
+                base_url: config.base_url,
+                api_key: username,
+                client_key: clientkey,
+                bridge_id: config.bridge_id,
+            },
+            Some(KeyResult::Error { kind: LINK_BUTTON_NOT_PRESSED, .. }) =>
+                return Err(anyhow!("Press the link button on the bridge, then pair again within 30 seconds.")),
+            Some(KeyResult::Error { description, .. }) => return Err(anyhow!("The bridge refused to pair: {description}")),
+            None => return Err(anyhow!("The bridge returned no result.")),
+        };
+
+        let saved_to = match &self.path {
+            Some(path) => {
+                let (target, json) = (path.clone(), serde_json::to_string_pretty(&bridge)?);
+                spawn_blocking(move || write_atomically(&target, &json)).await.context("Unable to save the bridge")??;
+                Some(path.display().to_string())
+            },
+            None => {
+                info!("DATA_DIR is not set, so the paired bridge's credentials weren't saved.");
+                None
+            },
+        };
+        Ok(PairResponse { bridge, saved_to })
+    }
+}
+
+/// The bridge's certificate is issued to its id, so this is the one request that can't check the hostname.
+async fn bridge_id(config: &HueConfig) -> anyhow::Result<String> {
+    let (builder, url) = client_builder(config)?;
+    let client = builder.danger_accept_invalid_hostnames(true).build().context("Unable to create the bridge HTTP client")?;
+    let info: BridgeInfo = client
+        .get(url.join("api/0/config")?)
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await
+        .context(format!("Unable to reach the bridge at {}", config.base_url))?
+        .json()
+        .await
+        .context("The bridge didn't say what its id is")?;
+    Ok(info.bridgeid.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use rocket::serde::json::json;
+    use crate::mock_bridge::MockBridge;
+    use super::{PairRequest, Pairing, PAIRED_FILE_NAME};
+
+    #[rocket::async_test]
+    async fn test_pair_with_stub_bridge() {
+        let bridge = MockBridge::start().await;
+        let dir = env::temp_dir().join(format!("rust-hue-pair-test-{}", std::process::id()));
+        let pairing = Pairing { path: Some(dir.join(PAIRED_FILE_NAME)), ca_cert_pem_path: None };
+        let request = || PairRequest { address: Some(bridge.base_url()), bridge_id: None };
+
+        bridge.respond("GET", "/api/0/config", 200, json!({ "name": "Hue Bridge", "bridgeid": "001788FFFE123456" }));
+        bridge.respond("POST", "/api", 200, json!([{ "error": { "type": 101, "address": "", "description": "link button not pressed" } }]));
+        let error = pairing.pair(request()).await.unwrap_err();
+        assert!(format!("{error:#}").contains("link button"));
+        assert_eq!(pairing.load().unwrap(), None);
+
+        bridge.respond("POST", "/api", 200, json!([{ "success": { "username": "new-key", "clientkey": "client-key" } }]));
+        let response = pairing.pair(request()).await.unwrap();
+        assert_eq!(response.bridge.api_key, "new-key");
+        assert_eq!(response.bridge.bridge_id.as_deref(), Some("001788fffe123456"));
+
+        let key_request = bridge.requests().into_iter().rev().find(|r| r.method == "POST").unwrap();
+        assert_eq!(key_request.json()["devicetype"], "rust-hue#hue-blend");
+        // Requests are sent to the bridge id, which is resolved to the given address.
+        assert_eq!(key_request.header("host").map(|h| h.starts_with("001788fffe123456")), Some(true));
+
+        let config = pairing.load().unwrap().unwrap();
+        assert_eq!(config.base_url, bridge.base_url());
+        assert_eq!(config.api_key, "new-key");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+use chrono::DateTime;
+use chrono_tz::Tz;
+use rocket::serde;
+
+use crate::time::{deserialize_utc, deserialize_utc_opt};
+
+/// A "hands off" switch: while active, the schedule and any overrides are ignored.
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Pause {
+	pub by: String,
+	#[serde(deserialize_with = "deserialize_utc")]
+	pub since: DateTime<Tz>,
+	/// `None` pauses until explicitly resumed.
+	#[serde(deserialize_with = "deserialize_utc_opt")]
+	pub until: Option<DateTime<Tz>>,
+}
+
+impl Pause {
+	pub fn new(by: String, now: DateTime<Tz>, until: Option<DateTime<Tz>>) -> anyhow::Result<Self> {
+		if let Some(until) = until {
+			if until <= now {
+				return Err(anyhow::anyhow!("Pause would end at {until}, which is not after now ({now})."));
+			}
+		}
+
+		Ok(Pause { by, since: now, until })
+	}
+
+	pub fn is_active(&self, now: &DateTime<Tz>) -> bool {
+		&self.since <= now && self.until.is_none_or(|until| now < &until)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::TimeZone;
+	use chrono_tz::{Tz, US::Eastern};
+	use super::Pause;
+
+	fn hm(hour: u32, minute: u32) -> chrono::DateTime<Tz> {
+		Eastern.with_ymd_and_hms(1999, 1, 1, hour, minute, 0).unwrap()
+	}
+
+	#[test]
+	fn test_pause_is_active() {
+		let pause = Pause::new(String::from("test"), hm(10, 0), Some(hm(12, 0))).unwrap();
+		assert!(!pause.is_active(&hm(9, 59)));
+		assert!(pause.is_active(&hm(10, 0)));
+		assert!(pause.is_active(&hm(11, 59)));
+		assert!(!pause.is_active(&hm(12, 0)));
+
+		let indefinite = Pause::new(String::from("test"), hm(10, 0), None).unwrap();
+		assert!(indefinite.is_active(&hm(23, 59)));
+	}
+
+	#[test]
+	fn test_invalid_pause() {
+		assert!(Pause::new(String::from("test"), hm(10, 0), Some(hm(10, 0))).is_err());
+	}
+}
@@ -0,0 +1,184 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+use notify::{RecursiveMode, Watcher};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    serde,
+    tokio::{
+        self,
+        signal::unix::{signal, SignalKind},
+        sync::mpsc,
+        time::sleep,
+    },
+    Orbit, Rocket,
+};
+
+use crate::{
+    audit::{AuditEntry, AuditEvent, AuditLog, Caller},
+    events::ChangeReason,
+    homes::{Home, Homes},
+};
+
+/// Editors and ConfigMap updates touch several files at once, so wait for them to settle.
+const FILE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Where an applied config came from.
+#[derive(Debug, PartialEq, Clone, Copy, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ReloadSource {
+    Startup,
+    FileWatch,
+    Signal,
+    Api,
+    Rollback,
+}
+
+impl fmt::Display for ReloadSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            ReloadSource::Startup => "startup",
+            ReloadSource::FileWatch => "file watch",
+            ReloadSource::Signal => "SIGHUP",
+            ReloadSource::Api => "API upload",
+            ReloadSource::Rollback => "rollback",
+        })
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct ReloadStatus {
+    at: DateTime<Tz>,
+    source: ReloadSource,
+    pub ok: bool,
+    error: Option<String>,
+}
+
+impl ReloadStatus {
+    pub fn new<T>(at: DateTime<Tz>, source: ReloadSource, result: &anyhow::Result<T>) -> Self {
+        ReloadStatus {
+            at,
+            source,
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+        }
+    }
+}
+
+/// Reloads attempted since startup, by whether they were applied.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct ReloadCounts {
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+/// Reloads every home's schedule when its file changes or the process receives SIGHUP.
+pub struct ScheduleReloader;
+
+#[rocket::async_trait]
+impl Fairing for ScheduleReloader {
+    fn info(&self) -> Info {
+        Info {
+            name: "ScheduleReloader",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let (Some(homes), Some(audit)) = (rocket.state::<Homes>(), rocket.state::<AuditLog>()) else {
+            error!("ScheduleReloader requires managed Homes and AuditLog.");
+            return;
+        };
+
+        for home in homes.iter() {
+            let target = ReloadTarget { home: home.clone(), audit: audit.clone() };
+            tokio::spawn(watch_signal(target.clone()));
+            let yaml_path = home.schedule.lock().await.yaml_path().map(PathBuf::from);
+            match yaml_path {
+                Some(path) => { tokio::spawn(watch_file(path, target)); },
+                None => warn!("Schedule for {home} was not loaded from a file, so it won't be watched."),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ReloadTarget {
+    home: Home,
+    audit: AuditLog,
+}
+
+async fn reload(target: &ReloadTarget, source: ReloadSource) {
+    let home = &target.home;
+    let mut guard = home.schedule.lock().await;
+    let now = (*guard).now();
+    let entry = AuditEntry::new(now, AuditEvent::Reload, &Caller::internal("reloader")).with_home(&home.id);
+    match (*guard).reload_from_file(now, source) {
+        Ok(()) => {
+            info!("Reloaded schedule for {home} ({source}).");
+            home.events.notify(ChangeReason::Reload);
+            target.audit.record(entry.with_detail(source.to_string()));
+        },
+        Err(e) => {
+            error!("Unable to reload schedule for {home} ({source}), keeping the previous one: {e:#}");
+            target.audit.record(entry.with_detail(format!("{source} failed: {e:#}")));
+        },
+    }
+}
+
+async fn watch_signal(target: ReloadTarget) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Unable to listen for SIGHUP: {e}");
+            return;
+        },
+    };
+
+    while hangups.recv().await.is_some() {
+        reload(&target, ReloadSource::Signal).await;
+    }
+}
+
+async fn watch_file(path: PathBuf, target: ReloadTarget) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Reading the file ourselves produces access events, which must not trigger another read.
+        if event.is_ok_and(|e| e.kind.is_create() || e.kind.is_modify() || e.kind.is_remove()) {
+            let _ = sender.send(());
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(w) => w,
+        Err(e) => {
+            warn!("Unable to create schedule file watcher: {e}");
+            return;
+        },
+    };
+
+    // Watch the directory rather than the file. k8s updates ConfigMap volumes by swapping a symlink,
+    // which replaces the file instead of modifying it.
+    let Some(dir) = path.parent() else {
+        warn!("Unable to find the directory of {}", path.display());
+        return;
+    };
+    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        warn!("Unable to watch {}: {e}", dir.display());
+        return;
+    }
+    info!("Watching {} for schedule changes.", dir.display());
+
+    while receiver.recv().await.is_some() {
+        sleep(FILE_DEBOUNCE).await;
+        while receiver.try_recv().is_ok() {}
+
+        // Other files in the directory may have changed, so only reload when ours did. If it
+        // can't be read, reload anyway so the failure shows up in /debug.
+        let needs_reload = target.home.schedule.lock().await.file_needs_reload();
+        if needs_reload.unwrap_or(true) {
+            reload(&target, ReloadSource::FileWatch).await;
+        }
+    }
+}
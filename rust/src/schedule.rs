@@ -1,13 +1,30 @@
-use std::{env, fmt, fs::File, io::BufReader, str::FromStr};
+use std::{env, fmt, fs, path::Path, str::FromStr};
 
 use anyhow::Context;
 use chrono::{DateTime, NaiveDate, TimeDelta};
 use chrono_tz::Tz;
 use rocket::serde;
 
-use crate::{sunset::get_sunset_time, time::{time_to_today_tz, tz_now}};
-
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+use crate::{
+	capabilities::LightConfig,
+	history::{ConfigHistory, HistoryEntry},
+	overrides::{active_override, apply_overrides, Expiry, Override, OverrideRequest, OverrideSource},
+	pause::Pause,
+	reload::{ReloadCounts, ReloadSource, ReloadStatus},
+	store::{PersistedState, StateStore},
+	sunset::{get_sunrise_time, get_sunset_time},
+	time::{time_to_today_tz, tz_now},
+	webhooks::WebhookConfig,
+};
+
+const DEFAULT_OVERRIDE_FADE_MINUTES: u32 = 10;
+/// Where homes other than the default keep their history and state, under the data dir.
+const HOMES_DIR: &str = "homes";
+const DEFAULT_LIGHTS_CYCLED_MAX_HOURS: u32 = 12;
+pub const MIN_MIREK: u16 = 153;
+pub const MAX_MIREK: u16 = 500;
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde")]
 struct LocationConfig {
 	longitude: f64,
@@ -15,7 +32,7 @@ struct LocationConfig {
 	timezone: String,
 }
 
-#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde", rename_all = "snake_case")]
 enum FromRefTime {
 	Sunset
@@ -40,7 +57,7 @@ impl FromStr for FromRefTime {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde", rename_all = "snake_case")]
 enum Action {
 	Color,
@@ -68,15 +85,15 @@ impl FromStr for Action {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde")]
-struct ChangeItem {
+pub struct ChangeItem {
 	action: Action,
     mirek: Option<u16>,
     brightness: Option<u8>,
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde")]
 struct RawScheduleItem {
 	hour: Option<i8>,
@@ -85,7 +102,7 @@ struct RawScheduleItem {
 	change: ChangeItem,
 }
 
-#[derive(Debug, PartialEq, Clone, serde::Serialize)]
+#[derive(Debug, PartialEq, Clone, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde")]
 struct ProcessedScheduleItem {
 	time: DateTime<Tz>,
@@ -115,21 +132,76 @@ impl ProcessedScheduleItem {
 	}
 }
 
-#[derive(Debug, serde::Deserialize)]
+/// A moment the schedule passed through.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Crossing {
+	Item { time: DateTime<Tz>, change: ChangeItem },
+	Sunset { time: DateTime<Tz> },
+}
+
+impl Crossing {
+	pub fn time(&self) -> DateTime<Tz> {
+		match self {
+			Crossing::Item { time, .. } | Crossing::Sunset { time } => *time,
+		}
+	}
+}
+
+/// Where the schedule is at a moment, for `/metrics`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ScheduleProgress {
+	pub change_action: ChangeAction,
+	/// Which of today's items was passed most recently.
+	pub segment_index: usize,
+	pub next_item_at: DateTime<Tz>,
+	pub sunset_at: DateTime<Tz>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", default)]
+struct OverrideConfig {
+	fade_minutes: u32,
+	/// How long an `until_lights_cycled` override lasts if the lights are never switched off and on.
+	lights_cycled_max_hours: u32,
+}
+
+impl Default for OverrideConfig {
+	fn default() -> Self {
+		OverrideConfig { fade_minutes: DEFAULT_OVERRIDE_FADE_MINUTES, lights_cycled_max_hours: DEFAULT_LIGHTS_CYCLED_MAX_HOURS }
+	}
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde")]
-struct ScheduleYamlConfig {
+pub struct ScheduleYamlConfig {
 	location: LocationConfig,
 	schedule: Vec<RawScheduleItem>,
+	#[serde(default)]
+	overrides: OverrideConfig,
+	/// Capabilities that differ from, or aren't reported by, the bridge.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	lights: Vec<LightConfig>,
+	/// Other systems to notify as the schedule passes items and sunset.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	webhooks: Vec<WebhookConfig>,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct UploadResult {
+	applied: bool,
+	processed_schedule: Vec<ProcessedScheduleItem>,
+	warnings: Vec<String>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde")]
 struct DebugSurrounding {
 	first: ProcessedScheduleItem,
 	last: ProcessedScheduleItem,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct DebugInfo {
 	just_updated: bool,
@@ -138,15 +210,45 @@ pub struct DebugInfo {
 	processed_schedule: Vec<ProcessedScheduleItem>,
 	now: DateTime<Tz>,
 	surrounding_items: DebugSurrounding,
+	scheduled_action: ChangeAction,
+	overrides: Vec<Override>,
+	pause: Option<Pause>,
+	last_reload: Option<ReloadStatus>,
 	change_action: ChangeAction,
 }
 
+impl DebugInfo {
+	pub fn now(&self) -> DateTime<Tz> {
+		self.now
+	}
+
+	pub fn change_action(&self) -> &ChangeAction {
+		&self.change_action
+	}
+}
+
 #[derive(Debug)]
 pub struct Schedule {
     tz: Tz,
 	location: LocationConfig,
 	raw_schedule: Vec<RawScheduleItem>,
 	todays_schedule: Option<Vec<ProcessedScheduleItem>>,
+	override_fade: TimeDelta,
+	lights_cycled_max: TimeDelta,
+	lights: Vec<LightConfig>,
+	webhooks: Vec<WebhookConfig>,
+	overrides: Vec<Override>,
+	next_override_id: u64,
+	pause: Option<Pause>,
+	/// The exact text the current config was parsed from.
+	source_yaml: String,
+	yaml_path: Option<String>,
+	/// The last text read from `yaml_path`, whether or not it was valid.
+	file_yaml: Option<String>,
+	last_reload: Option<ReloadStatus>,
+	reload_counts: ReloadCounts,
+	history: ConfigHistory,
+	store: StateStore,
 }
 
 impl Schedule {
@@ -163,7 +265,11 @@ impl Schedule {
 			let (first, last) = self.get_surrounding_schedule_items(now)?;
 			DebugSurrounding { first: first.clone(), last: last.clone() }
 		};
-		let change_action = self.get_action_for_now(&now)?;
+		let scheduled_action = self.get_action_for_now(&now)?;
+		let change_action = match self.active_pause(&now) {
+			Some(_) => ChangeAction::None,
+			None => apply_overrides(&self.overrides, &now, scheduled_action.clone()),
+		};
 
 		Ok(DebugInfo {
 			tz: self.tz.to_string(),
@@ -172,21 +278,89 @@ impl Schedule {
 			processed_schedule: todays_schedule,
 			now,
 			surrounding_items,
+			scheduled_action,
+			overrides: self.overrides.clone(),
+			pause: self.pause.clone(),
+			last_reload: self.last_reload.clone(),
 			change_action,
 		})
 	}
 
 	pub fn new() -> anyhow::Result<Self> {
-		Self::from_env("SCHEDULE_YAML_PATH")
+		let mut schedule = Self::from_env("SCHEDULE_YAML_PATH")?;
+		schedule.attach_storage(Path::new(""));
+		Ok(schedule)
+	}
+
+	/// The schedule for one of several homes. Its history and state are kept in a subdirectory of the data dir named after it.
+	pub fn for_home(id: &str, yaml_path: String) -> anyhow::Result<Self> {
+		let mut schedule = Self::from_file(yaml_path)?;
+		schedule.attach_storage(&Path::new(HOMES_DIR).join(id));
+		Ok(schedule)
+	}
+
+	fn attach_storage(&mut self, data_sub_dir: &Path) {
+		self.history = ConfigHistory::from_env("DATA_DIR", data_sub_dir, "SCHEDULE_HISTORY_LIMIT");
+
+		self.store = StateStore::from_env("DATA_DIR", data_sub_dir);
+
+		let now = self.now();
+		self.history.record(now.fixed_offset(), ReloadSource::Startup, &self.source_yaml);
+		match self.store.load() {
+			Ok(Some(state)) => self.restore_state(state),
+			Ok(None) => {},
+			Err(e) => warn!("Ignoring unreadable state, starting without overrides or a pause: {e:#}"),
+		}
+	}
+
+	/// Brings back overrides and the pause from a previous run. Ones that have since ended are dropped on the next update.
+	fn restore_state(&mut self, state: PersistedState) {
+		let tz = self.tz;
+		self.overrides = state.overrides.into_iter().map(|mut o| {
+			o.created = o.created.with_timezone(&tz);
+			o.expires = o.expires.with_timezone(&tz);
+			o.fade_end = o.fade_end.with_timezone(&tz);
+			o
+		}).collect();
+		self.next_override_id = state.next_override_id.max(self.overrides.iter().map(|o| o.id + 1).max().unwrap_or(1));
+		self.pause = state.pause.map(|mut p| {
+			p.since = p.since.with_timezone(&tz);
+			p.until = p.until.map(|until| until.with_timezone(&tz));
+			p
+		});
+		info!("Restored {} override(s) and {} pause.", self.overrides.len(), if self.pause.is_some() { "a" } else { "no" });
+	}
+
+	/// Writes overrides and the pause through to the data dir. Failing to do so shouldn't fail the request that changed them.
+	fn persist_state(&self) {
+		let state = PersistedState {
+			overrides: self.overrides.clone(),
+			next_override_id: self.next_override_id,
+			pause: self.pause.clone(),
+		};
+		if let Err(e) = self.store.save(&state) {
+			warn!("Unable to persist state: {e:#}");
+		}
 	}
 
 	fn from_env(env_path_var: &str) -> anyhow::Result<Self> {
 		let yaml_path = env::var(env_path_var)
 			.context(format!("Unable to find env var: {env_path_var}"))?;
-		let schedule_file = File::open(&yaml_path)
+		Self::from_file(yaml_path)
+	}
+
+	fn from_file(yaml_path: String) -> anyhow::Result<Self> {
+		let yaml = fs::read_to_string(&yaml_path)
 			.context(format!("Unable to open file at {}", &yaml_path))?;
-		let reader = BufReader::new(schedule_file);
-		let schedule_yaml_config: ScheduleYamlConfig = serde_yaml::from_reader(reader)
+
+		let mut schedule = Self::from_yaml(&yaml)?;
+		schedule.yaml_path = Some(yaml_path);
+		schedule.file_yaml = Some(yaml);
+		Ok(schedule)
+	}
+
+	pub(crate) fn from_yaml(yaml: &str) -> anyhow::Result<Self> {
+		let schedule_yaml_config: ScheduleYamlConfig = serde_yaml::from_str(yaml)
 			.context("Unable to parse schedule yaml file.")?;
 		let tz = match schedule_yaml_config.location.timezone.parse::<Tz>() {
 			Ok(tz) => Ok(tz),
@@ -201,9 +375,169 @@ impl Schedule {
 			location: schedule_yaml_config.location,
 			raw_schedule: schedule_yaml_config.schedule,
 			todays_schedule: None,
+			override_fade: TimeDelta::minutes(schedule_yaml_config.overrides.fade_minutes.into()),
+			lights_cycled_max: TimeDelta::hours(schedule_yaml_config.overrides.lights_cycled_max_hours.into()),
+			lights: schedule_yaml_config.lights,
+			webhooks: schedule_yaml_config.webhooks,
+			overrides: Vec::new(),
+			next_override_id: 1,
+			pause: None,
+			source_yaml: yaml.to_string(),
+			yaml_path: None,
+			file_yaml: None,
+			last_reload: None,
+			reload_counts: ReloadCounts::default(),
+			history: ConfigHistory::default(),
+			store: StateStore::default(),
 		})
 	}
 
+	/// Parses `yaml` (or JSON, which is valid YAML) and checks that it can produce a schedule for `now`.
+	fn validated_candidate(yaml: &str, now: &DateTime<Tz>) -> anyhow::Result<Schedule> {
+		let mut candidate = Self::from_yaml(yaml)?;
+		candidate.set_today(now).context("New schedule is invalid for today.")?;
+		Ok(candidate)
+	}
+
+	/// Swaps in a validated `candidate`'s config while keeping runtime state such as overrides and pauses.
+	fn replace_config(&mut self, candidate: Schedule, now: &DateTime<Tz>, source: ReloadSource) {
+		self.history.record(now.fixed_offset(), source, &candidate.source_yaml);
+
+		self.tz = candidate.tz;
+		self.location = candidate.location;
+		self.raw_schedule = candidate.raw_schedule;
+		self.todays_schedule = candidate.todays_schedule;
+		self.override_fade = candidate.override_fade;
+		self.lights_cycled_max = candidate.lights_cycled_max;
+		self.lights = candidate.lights;
+		self.webhooks = candidate.webhooks;
+		self.source_yaml = candidate.source_yaml;
+	}
+
+	fn read_yaml_file(&self) -> anyhow::Result<String> {
+		let yaml_path = self.yaml_path.as_ref().context("Schedule was not loaded from a file.")?;
+		fs::read_to_string(yaml_path).context(format!("Unable to open file at {yaml_path}"))
+	}
+
+	pub fn yaml_path(&self) -> Option<&str> {
+		self.yaml_path.as_deref()
+	}
+
+	/// Whether the schedule file has changed since it was last read.
+	pub fn file_needs_reload(&self) -> anyhow::Result<bool> {
+		Ok(Some(self.read_yaml_file()?) != self.file_yaml)
+	}
+
+	/// Re-reads the schedule file, keeping the current config if the new one is invalid.
+	pub fn reload_from_file(&mut self, now: DateTime<Tz>, source: ReloadSource) -> anyhow::Result<()> {
+		let result = self.read_yaml_file().and_then(|yaml| {
+			let candidate = Self::validated_candidate(&yaml, &now);
+			self.file_yaml = Some(yaml);
+			self.replace_config(candidate?, &now, source);
+			Ok(())
+		});
+
+		self.record_reload(ReloadStatus::new(now, source, &result));
+		result
+	}
+
+	/// Validates an uploaded config and, unless `dry_run` is set, replaces the current one with it.
+	pub fn upload(&mut self, body: &str, now: DateTime<Tz>, dry_run: bool) -> anyhow::Result<UploadResult> {
+		let candidate = Self::validated_candidate(body, &now);
+		if !dry_run {
+			self.record_reload(ReloadStatus::new(now, ReloadSource::Api, &candidate));
+		}
+		let candidate = candidate?;
+
+		let upload_result = UploadResult {
+			applied: !dry_run,
+			processed_schedule: candidate.todays_schedule.clone().unwrap_or_default(),
+			warnings: candidate.warnings(),
+		};
+		if !dry_run {
+			self.replace_config(candidate, &now, ReloadSource::Api);
+		}
+		Ok(upload_result)
+	}
+
+	/// Re-applies a config from the history. It's validated again since it may not work for today.
+	pub fn rollback(&mut self, id: u64, now: DateTime<Tz>) -> anyhow::Result<()> {
+		let entry = self.history.get(id).context(format!("No schedule history entry with id {id}."))?;
+		let candidate = Self::validated_candidate(&entry.yaml, &now);
+
+		self.record_reload(ReloadStatus::new(now, ReloadSource::Rollback, &candidate));
+		self.replace_config(candidate?, &now, ReloadSource::Rollback);
+		Ok(())
+	}
+
+	fn record_reload(&mut self, status: ReloadStatus) {
+		match status.ok {
+			true => self.reload_counts.succeeded += 1,
+			false => self.reload_counts.failed += 1,
+		}
+		self.last_reload = Some(status);
+	}
+
+	/// How many reloads have been attempted since startup.
+	pub fn reload_counts(&self) -> ReloadCounts {
+		self.reload_counts
+	}
+
+	pub fn history(&self) -> &[HistoryEntry] {
+		self.history.entries()
+	}
+
+	/// Problems that don't stop the schedule from loading, but probably aren't intended.
+	fn warnings(&self) -> Vec<String> {
+		let mut warnings = Vec::new();
+		for (i, item) in self.raw_schedule.iter().enumerate() {
+			let change = &item.change;
+			if change.action == Action::Color && (change.mirek.is_none() || change.brightness.is_none()) {
+				warnings.push(format!("[{i}] color item needs both mirek and brightness, so blending with it will fail."));
+			}
+			if let Some(mirek) = change.mirek.filter(|m| !(MIN_MIREK..=MAX_MIREK).contains(m)) {
+				warnings.push(format!("[{i}] mirek {mirek} is outside of the {MIN_MIREK}-{MAX_MIREK} range most lights support."));
+			}
+			if let Some(brightness) = change.brightness.filter(|b| *b > 100) {
+				warnings.push(format!("[{i}] brightness {brightness} is over 100."));
+			}
+		}
+
+		for light in &self.lights {
+			if let Some(warning) = light.warning() {
+				warnings.push(warning);
+			}
+		}
+		for (i, webhook) in self.webhooks.iter().enumerate() {
+			if let Some(warning) = webhook.warning() {
+				warnings.push(format!("webhooks[{i}]: {warning}"));
+			}
+		}
+
+		let todays_schedule = self.todays_schedule.as_deref().unwrap_or_default();
+		for (i, pair) in todays_schedule.windows(2).enumerate() {
+			if pair[0].time == pair[1].time {
+				warnings.push(format!("[{i}] and [{}] are both at {}, so [{i}] never takes effect.", i + 1, pair[0].time));
+			}
+		}
+		warnings
+	}
+
+	/// The active config, in the same shape it is loaded from.
+	pub fn config(&self) -> ScheduleYamlConfig {
+		ScheduleYamlConfig {
+			location: self.location.clone(),
+			schedule: self.raw_schedule.clone(),
+			overrides: OverrideConfig {
+				fade_minutes: self.override_fade.num_minutes() as u32,
+				lights_cycled_max_hours: self.lights_cycled_max.num_hours() as u32,
+			},
+			lights: self.lights.clone(),
+			webhooks: self.webhooks.clone(),
+		}
+	}
+
+	#[tracing::instrument(name = "sunset", skip_all)]
 	fn get_sunset_time(&self, now: &DateTime<Tz>) -> anyhow::Result<DateTime<Tz>> {
 		match get_sunset_time(self.location.latitude, self.location.longitude, self.tz, now) {
 			Ok(time) => Ok(time),
@@ -211,6 +545,21 @@ impl Schedule {
 		}
 	}
 
+	/// The first sunrise that is after `now`, which may be tomorrow's.
+	fn get_next_sunrise_time(&self, now: &DateTime<Tz>) -> anyhow::Result<DateTime<Tz>> {
+		let sunrise = |day: &DateTime<Tz>| match get_sunrise_time(self.location.latitude, self.location.longitude, self.tz, day) {
+			Ok(time) => Ok(time),
+			Err(e) => Err(anyhow::Error::msg(e.to_string())),
+		};
+
+		let today = sunrise(now)?;
+		if *now < today {
+			return Ok(today);
+		}
+		sunrise(&(*now + TimeDelta::days(1)))
+	}
+
+	#[tracing::instrument(skip_all)]
 	pub fn try_update(&mut self, now: DateTime<Tz>) -> anyhow::Result<bool> {
 		let updated = if self.todays_schedule.is_none() {
 			self.set_today(&now)?;
@@ -232,6 +581,7 @@ impl Schedule {
 		Ok(updated)
 	}
 
+	#[tracing::instrument(skip_all, fields(items = self.raw_schedule.len()))]
 	pub fn set_today(&mut self, now: &DateTime<Tz>) -> anyhow::Result<()> {
 		let sunset_time = self.get_sunset_time(now).context("Unable to get sunset time.")?;
 
@@ -275,6 +625,7 @@ impl Schedule {
 		}
 	}
 
+	#[tracing::instrument(name = "surrounding_items", skip_all)]
 	fn get_surrounding_schedule_items(&self, now: DateTime<Tz>) -> anyhow::Result<(&ProcessedScheduleItem, &ProcessedScheduleItem)> {
 		let todays_schedule = self.todays_schedule
 			.as_ref()
@@ -290,11 +641,198 @@ impl Schedule {
 		blend_actions(a, b, now)
 	}
 
+	/// Refreshes today's schedule if needed, then blends the action for `now`, taking overrides and pauses into account.
+	#[tracing::instrument(skip_all, fields(now = %now))]
+	pub fn update_and_get_action(&mut self, now: &DateTime<Tz>) -> anyhow::Result<(bool, ChangeAction)> {
+		let updated = self.try_update(*now)?;
+		let override_count = self.overrides.len();
+		self.overrides.retain(|o| !o.is_finished(now));
+		let pause_ended = self.pause.as_ref().is_some_and(|p| p.until.is_some_and(|until| until <= *now));
+		if pause_ended {
+			self.pause = None;
+		}
+		if pause_ended || self.overrides.len() != override_count {
+			self.persist_state();
+		}
+
+		let scheduled_action = self.get_action_for_now(now)?;
+		if self.active_pause(now).is_some() {
+			return Ok((updated, ChangeAction::None));
+		}
+		Ok((updated, apply_overrides(&self.overrides, now, scheduled_action)))
+	}
+
+	pub fn progress(&mut self, now: &DateTime<Tz>) -> anyhow::Result<ScheduleProgress> {
+		let (_, change_action) = self.update_and_get_action(now)?;
+		let segment_index = self.segment_index(now)?;
+		let todays_schedule = self.todays_schedule.as_deref().context("todays_schedule has not been set.")?;
+		let next_item_at = todays_schedule
+			.get(segment_index + 1)
+			.context(format!("now ({now}) is after today's last item."))?
+			.time;
+
+		Ok(ScheduleProgress {
+			change_action,
+			segment_index,
+			next_item_at,
+			sunset_at: self.get_sunset_time(now).context("Unable to get sunset time.")?,
+		})
+	}
+
+	/// Which of today's items was passed most recently. Unlike `progress`, this doesn't update the schedule first.
+	pub fn segment_index(&self, now: &DateTime<Tz>) -> anyhow::Result<usize> {
+		self.todays_schedule
+			.as_deref()
+			.context("todays_schedule has not been set.")?
+			.iter()
+			.rposition(|item| item.time <= *now)
+			.context(format!("now ({now}) is before today's first item."))
+	}
+
+	/// Pauses for `minutes`, or until resumed if `None`. Replaces any existing pause.
+	pub fn pause(&mut self, by: String, now: DateTime<Tz>, minutes: Option<u32>) -> anyhow::Result<Pause> {
+		let until = minutes.map(|m| now + TimeDelta::minutes(m.into()));
+		let pause = Pause::new(by, now, until)?;
+		self.pause = Some(pause.clone());
+		self.persist_state();
+		Ok(pause)
+	}
+
+	/// Resumes now, or after `after_minutes` if given. Returns the pause that is still in effect, if any.
+	pub fn resume(&mut self, now: DateTime<Tz>, after_minutes: Option<u32>) -> anyhow::Result<Option<Pause>> {
+		let Some(minutes) = after_minutes else {
+			self.pause = None;
+			self.persist_state();
+			return Ok(None);
+		};
+
+		let pause = self.pause
+			.as_mut()
+			.filter(|p| p.is_active(&now))
+			.context("Cannot schedule a resume while not paused.")?;
+		pause.until = Some(now + TimeDelta::minutes(minutes.into()));
+		let pause = pause.clone();
+		self.persist_state();
+		Ok(Some(pause))
+	}
+
+	pub fn active_pause(&self, now: &DateTime<Tz>) -> Option<&Pause> {
+		self.pause.as_ref().filter(|p| p.is_active(now))
+	}
+
+	pub fn add_override(&mut self, request: &OverrideRequest, now: DateTime<Tz>) -> anyhow::Result<Override> {
+		self.add_override_from(request, now, OverrideSource::Api)
+	}
+
+	fn add_override_from(&mut self, request: &OverrideRequest, now: DateTime<Tz>, source: OverrideSource) -> anyhow::Result<Override> {
+		let expires = self.override_expires(&request.expiry, now)?;
+		let fade = match request.fade_minutes {
+			Some(minutes) => TimeDelta::minutes(minutes.into()),
+			None => self.override_fade,
+		};
+
+		let new_override = Override { source, ..Override::new(self.next_override_id, request, now, expires, fade)? };
+		self.next_override_id += 1;
+		self.overrides.push(new_override.clone());
+		self.persist_state();
+		Ok(new_override)
+	}
+
+	fn override_expires(&mut self, expiry: &Expiry, now: DateTime<Tz>) -> anyhow::Result<DateTime<Tz>> {
+		Ok(match expiry {
+			Expiry::ForMinutes(minutes) => now + TimeDelta::minutes((*minutes).into()),
+			Expiry::UntilNextItem => {
+				self.try_update(now)?;
+				self.get_surrounding_schedule_items(now)?.1.time
+			},
+			Expiry::UntilSunrise => self.get_next_sunrise_time(&now).context("Unable to get sunrise time.")?,
+			Expiry::UntilLightsCycled => now + self.lights_cycled_max,
+		})
+	}
+
+	/// Holds off after a manual change. While an earlier hold hasn't expired, it's extended rather than
+	/// another being added, so there's only ever one to cancel. Returns the hold, and whether it's new.
+	pub fn hold_for_manual_change(&mut self, request: &OverrideRequest, now: DateTime<Tz>) -> anyhow::Result<(Override, bool)> {
+		let held = self.overrides.iter().position(|o| o.source == OverrideSource::ManualChange && now < o.expires);
+		let Some(index) = held else {
+			return Ok((self.add_override_from(request, now, OverrideSource::ManualChange)?, true));
+		};
+
+		let expires = self.override_expires(&request.expiry, now)?;
+		let held = &mut self.overrides[index];
+		if expires > held.expires {
+			let fade = held.fade_end - held.expires;
+			held.expires = expires;
+			held.fade_end = expires + fade;
+		}
+		let held = held.clone();
+		self.persist_state();
+		Ok((held, false))
+	}
+
+	/// Cancels one override, or all of them if `id` is `None`. Returns how many were cancelled.
+	pub fn cancel_overrides(&mut self, id: Option<u64>) -> usize {
+		let before = self.overrides.len();
+		self.overrides.retain(|o| id.is_some_and(|id| o.id != id));
+		let cancelled = before - self.overrides.len();
+		if cancelled > 0 {
+			self.persist_state();
+		}
+		cancelled
+	}
+
+	/// Cancels every override `source` added, returning their ids.
+	pub fn cancel_overrides_from(&mut self, source: OverrideSource) -> Vec<u64> {
+		let ids: Vec<u64> = self.overrides.iter().filter(|o| o.source == source).map(|o| o.id).collect();
+		if !ids.is_empty() {
+			self.overrides.retain(|o| o.source != source);
+			self.persist_state();
+		}
+		ids
+	}
+
+	pub fn light_configs(&self) -> &[LightConfig] {
+		&self.lights
+	}
+
+	pub fn webhook_configs(&self) -> &[WebhookConfig] {
+		&self.webhooks
+	}
+
+	/// Schedule items and sunsets after `from`, up to and including `to`, oldest first.
+	pub fn crossings(&self, from: &DateTime<Tz>, to: &DateTime<Tz>) -> anyhow::Result<Vec<Crossing>> {
+		let mut crossings = Vec::new();
+		let mut day = from.date_naive();
+		while day <= to.date_naive() {
+			let noon = time_to_today_tz(&self.tz, day, 12, 0)?;
+			let sunset_time = self.get_sunset_time(&noon).context("Unable to get sunset time.")?;
+			crossings.push(Crossing::Sunset { time: sunset_time });
+			for raw_item in &self.raw_schedule {
+				let item = ProcessedScheduleItem::from(&self.tz, raw_item, day, &sunset_time)?;
+				crossings.push(Crossing::Item { time: item.time, change: item.change });
+			}
+			day = day.succ_opt().context("Ran out of days while looking for crossings.")?;
+		}
+
+		crossings.retain(|crossing| crossing.time() > *from && crossing.time() <= *to);
+		crossings.sort_by_key(Crossing::time);
+		Ok(crossings)
+	}
+
+	pub fn overrides(&self) -> &[Override] {
+		&self.overrides
+	}
+
+	pub fn active_override(&self, now: &DateTime<Tz>) -> Option<&Override> {
+		active_override(&self.overrides, now)
+	}
+
 	pub fn now(&self) -> DateTime<Tz> {
 		tz_now(&self.tz)
 	}
 }
 
+#[tracing::instrument(name = "blend", skip_all)]
 fn blend_actions(a: &ProcessedScheduleItem, b: &ProcessedScheduleItem, now: &DateTime<Tz>) -> anyhow::Result<ChangeAction> {
 	if a.time > b.time {
 		return Err(anyhow::anyhow!("a.time ({a:?}) should not be after b.time ({b:?})"));
@@ -358,12 +896,12 @@ fn get_surrounding_schedule_items(schedule: &[ProcessedScheduleItem], now: DateT
 	Err(anyhow::anyhow!("now ({now}) has reached an unknown error."))
 }
 
-fn fraction<T>(a_factor: f64, a_value: T, b_factor: f64, b_value: T) -> f64
+pub fn fraction<T>(a_factor: f64, a_value: T, b_factor: f64, b_value: T) -> f64
 where T: Into<f64>{
 	a_factor * a_value.into() + b_factor * b_value.into()
 }
 
-#[derive(Debug, PartialEq, serde::Serialize)]
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 #[serde(crate = "rocket::serde", rename_all = "snake_case")]
 pub enum ChangeAction {
 	None,
@@ -414,9 +952,20 @@ mod tests {
 	}
 
 	mod schedule_tests {
-		use chrono::{Datelike, TimeZone};
+		use chrono::{Datelike, TimeDelta, TimeZone, Timelike};
 		use chrono_tz::Tz;
-		use crate::schedule::{Action, ChangeItem, LocationConfig, RawScheduleItem, Schedule};
+		use std::{env, fs};
+		use crate::history::ConfigHistory;
+		use crate::overrides::{Expiry, OverrideRequest};
+		use crate::reload::{ReloadCounts, ReloadSource};
+		use crate::store::{PersistedState, StateStore};
+		use crate::telemetry::file_layer;
+		use rocket::serde::json::serde_json;
+		use tracing_subscriber::layer::SubscriberExt;
+		use crate::schedule::{
+			Action, ChangeAction, ChangeItem, Crossing, LocationConfig, RawScheduleItem, Schedule, DEFAULT_LIGHTS_CYCLED_MAX_HOURS,
+			DEFAULT_OVERRIDE_FADE_MINUTES,
+		};
 		use super::{get_naive_datetime, TEST_TZ};
 
 		fn get_tz_datetime_dhm(day: u32, hour: u32, minute: u32) -> chrono::DateTime<Tz> {
@@ -436,6 +985,20 @@ mod tests {
 					},
 					raw_schedule,
 					todays_schedule: None,
+					override_fade: TimeDelta::zero(),
+					lights_cycled_max: TimeDelta::hours(DEFAULT_LIGHTS_CYCLED_MAX_HOURS.into()),
+					lights: Vec::new(),
+					webhooks: Vec::new(),
+					overrides: Vec::new(),
+					next_override_id: 1,
+					pause: None,
+					source_yaml: String::new(),
+					yaml_path: None,
+					file_yaml: None,
+					last_reload: None,
+					reload_counts: ReloadCounts::default(),
+					history: ConfigHistory::default(),
+					store: StateStore::default(),
 				}
 			}
 		}
@@ -482,6 +1045,225 @@ mod tests {
 			assert_eq!(schedule2_after.len(), 3);
 			assert_eq!(schedule2_after[0].time.day(), 2);
 		}
+
+		#[test]
+		fn override_test() {
+			let mut schedule = Schedule::new_for_test(vec![
+				fake_schedule_item(1, 0), fake_schedule_item(10, 30),
+			]);
+			let now = get_tz_datetime_dhm(1, 9, 0);
+			let request = OverrideRequest {
+				change_action: ChangeAction::None,
+				expiry: Expiry::UntilNextItem,
+				fade_minutes: None,
+			};
+
+			let added = schedule.add_override(&request, now).unwrap();
+			assert_eq!(added.expires, get_tz_datetime_dhm(1, 10, 30));
+			assert_eq!(schedule.update_and_get_action(&now).unwrap().1, ChangeAction::None);
+
+			assert_eq!(schedule.cancel_overrides(Some(added.id + 1)), 0);
+			assert_eq!(schedule.cancel_overrides(Some(added.id)), 1);
+			assert_ne!(schedule.update_and_get_action(&now).unwrap().1, ChangeAction::None);
+
+			let request = OverrideRequest { expiry: Expiry::UntilLightsCycled, ..request };
+			assert_eq!(schedule.add_override(&request, now).unwrap().expires, now + TimeDelta::hours(12));
+			schedule.lights_cycled_max = TimeDelta::hours(3);
+			assert_eq!(schedule.add_override(&request, now).unwrap().expires, now + TimeDelta::hours(3));
+		}
+
+		fn fake_yaml(first_hour: i8) -> String {
+			format!("
+location:
+  longitude: -74.0
+  latitude: 40.7
+  timezone: US/Eastern
+schedule:
+  - hour: {first_hour}
+    change: {{ action: color, mirek: 250, brightness: 100 }}
+  - hour: 20
+    change: {{ action: stop }}
+")
+		}
+
+		#[test]
+		fn override_config_test() {
+			let yaml = format!("{}overrides: {{ lights_cycled_max_hours: 2 }}\n", fake_yaml(6));
+			let schedule = Schedule::from_yaml(&yaml).unwrap();
+			assert_eq!(schedule.lights_cycled_max, TimeDelta::hours(2));
+			assert_eq!(schedule.override_fade, TimeDelta::minutes(DEFAULT_OVERRIDE_FADE_MINUTES.into()));
+			assert_eq!(schedule.config().overrides.lights_cycled_max_hours, 2);
+		}
+
+		#[test]
+		fn reload_test() {
+			let path = env::temp_dir().join(format!("rust-hue-reload-test-{}.yml", std::process::id()));
+			fs::write(&path, fake_yaml(6)).unwrap();
+			let mut schedule = Schedule::from_yaml(&fake_yaml(6)).unwrap();
+			schedule.yaml_path = Some(path.to_string_lossy().to_string());
+			schedule.file_yaml = Some(fake_yaml(6));
+			let now = get_tz_datetime_dhm(1, 12, 0);
+			assert!(!schedule.file_needs_reload().unwrap());
+
+			fs::write(&path, fake_yaml(7)).unwrap();
+			assert!(schedule.file_needs_reload().unwrap());
+			schedule.reload_from_file(now, ReloadSource::Signal).unwrap();
+			assert_eq!(schedule.raw_schedule[0].hour, Some(7));
+			assert!(schedule.last_reload.as_ref().unwrap().ok);
+
+			// Items out of order can only be caught by processing them, which must keep the old config.
+			fs::write(&path, fake_yaml(21)).unwrap();
+			assert!(schedule.reload_from_file(now, ReloadSource::FileWatch).is_err());
+			assert_eq!(schedule.raw_schedule[0].hour, Some(7));
+			assert!(!schedule.last_reload.as_ref().unwrap().ok);
+			assert!(!schedule.file_needs_reload().unwrap());
+
+			fs::remove_file(&path).unwrap();
+		}
+
+		#[test]
+		fn upload_test() {
+			let mut schedule = Schedule::from_yaml(&fake_yaml(6)).unwrap();
+			let now = get_tz_datetime_dhm(1, 12, 0);
+			let json = r#"{
+				"location": {"longitude": -74.0, "latitude": 40.7, "timezone": "US/Eastern"},
+				"schedule": [
+					{"hour": 8, "change": {"action": "color", "mirek": 100}},
+					{"hour": 20, "change": {"action": "stop"}}
+				]
+			}"#;
+
+			let dry_run = schedule.upload(json, now, true).unwrap();
+			assert!(!dry_run.applied);
+			assert_eq!(dry_run.processed_schedule.len(), 3);
+			assert_eq!(dry_run.warnings.len(), 2);
+			assert_eq!(schedule.raw_schedule[0].hour, Some(6));
+			assert!(schedule.last_reload.is_none());
+
+			assert!(schedule.upload(&fake_yaml(21), now, false).is_err());
+			assert_eq!(schedule.raw_schedule[0].hour, Some(6));
+			assert!(!schedule.last_reload.as_ref().unwrap().ok);
+
+			assert!(schedule.upload(json, now, false).unwrap().applied);
+			assert_eq!(schedule.raw_schedule[0].hour, Some(8));
+			assert_eq!(schedule.config().schedule.len(), 2);
+		}
+
+		#[test]
+		fn rollback_test() {
+			let mut schedule = Schedule::from_yaml(&fake_yaml(6)).unwrap();
+			let now = get_tz_datetime_dhm(1, 12, 0);
+			schedule.history.record(now.fixed_offset(), ReloadSource::Startup, &fake_yaml(6));
+			schedule.upload(&fake_yaml(7), now, false).unwrap();
+			assert_eq!(schedule.history().len(), 2);
+
+			assert!(schedule.rollback(3, now).is_err());
+			schedule.rollback(1, now).unwrap();
+			assert_eq!(schedule.raw_schedule[0].hour, Some(6));
+			assert_eq!(schedule.history().len(), 3);
+			assert_eq!(schedule.history()[2].source, ReloadSource::Rollback);
+			assert_eq!(schedule.history()[2].hash, schedule.history()[0].hash);
+		}
+
+		#[test]
+		fn progress_test() {
+			let mut schedule = Schedule::from_yaml(&fake_yaml(6)).unwrap();
+			let progress = schedule.progress(&get_tz_datetime_dhm(1, 18, 0)).unwrap();
+			assert_eq!(progress.segment_index, 0);
+			assert_eq!(progress.next_item_at, get_tz_datetime_dhm(1, 20, 0));
+			assert_eq!(progress.sunset_at.hour(), 16);
+			assert_ne!(progress.change_action, ChangeAction::None);
+
+			assert!(schedule.upload(&fake_yaml(21), get_tz_datetime_dhm(1, 18, 0), false).is_err());
+			assert_eq!(schedule.reload_counts(), ReloadCounts { succeeded: 0, failed: 1 });
+		}
+
+		#[test]
+		fn spans_test() {
+			let path = env::temp_dir().join(format!("rust-hue-spans-test-{}.jsonl", std::process::id()));
+			let subscriber = tracing_subscriber::registry().with(file_layer(fs::File::create(&path).unwrap()));
+			let mut schedule = Schedule::from_yaml(&fake_yaml(6)).unwrap();
+			tracing::subscriber::with_default(subscriber, || {
+				schedule.update_and_get_action(&get_tz_datetime_dhm(1, 18, 0)).unwrap();
+			});
+
+			// Each line is a span closing, after the spans it's nested in, outermost first.
+			let nesting: Vec<Vec<String>> = fs::read_to_string(&path).unwrap().lines().map(|line| {
+				let json: serde_json::Value = serde_json::from_str(line).unwrap();
+				json["spans"].as_array().unwrap().iter().chain([&json["span"]])
+					.map(|span| span["name"].as_str().unwrap().to_string())
+					.collect()
+			}).collect();
+			assert_eq!(nesting, [
+				vec!["update_and_get_action", "try_update", "set_today", "sunset"],
+				vec!["update_and_get_action", "try_update", "set_today"],
+				vec!["update_and_get_action", "try_update"],
+				vec!["update_and_get_action", "surrounding_items"],
+				vec!["update_and_get_action", "blend"],
+				vec!["update_and_get_action"],
+			]);
+
+			fs::remove_file(&path).unwrap();
+		}
+
+		#[test]
+		fn crossings_test() {
+			let schedule = Schedule::from_yaml(&fake_yaml(6)).unwrap();
+			let crossings = schedule.crossings(&get_tz_datetime_dhm(1, 12, 0), &get_tz_datetime_dhm(2, 6, 0)).unwrap();
+
+			assert_eq!(crossings.len(), 3);
+			assert!(matches!(crossings[0], Crossing::Sunset { time } if time.hour() == 16));
+			assert!(matches!(&crossings[1], Crossing::Item { change, .. } if change.action == Action::Stop));
+			assert_eq!(crossings[2].time(), get_tz_datetime_dhm(2, 6, 0));
+			assert!(schedule.crossings(&get_tz_datetime_dhm(2, 6, 0), &get_tz_datetime_dhm(2, 7, 0)).unwrap().is_empty());
+		}
+
+		#[test]
+		fn pause_test() {
+			let mut schedule = Schedule::new_for_test(vec![
+				fake_schedule_item(1, 0), fake_schedule_item(10, 30),
+			]);
+			let now = get_tz_datetime_dhm(1, 9, 0);
+
+			assert!(schedule.resume(now, Some(5)).is_err());
+			schedule.pause(String::from("test"), now, None).unwrap();
+			assert_eq!(schedule.update_and_get_action(&now).unwrap().1, ChangeAction::None);
+
+			let resume_at = get_tz_datetime_dhm(1, 9, 5);
+			assert_eq!(schedule.resume(now, Some(5)).unwrap().unwrap().until, Some(resume_at));
+			assert_eq!(schedule.update_and_get_action(&get_tz_datetime_dhm(1, 9, 4)).unwrap().1, ChangeAction::None);
+			assert_ne!(schedule.update_and_get_action(&resume_at).unwrap().1, ChangeAction::None);
+			assert!(schedule.active_pause(&resume_at).is_none());
+		}
+
+		#[test]
+		fn restore_state_test() {
+			let mut schedule = Schedule::new_for_test(vec![
+				fake_schedule_item(1, 0), fake_schedule_item(10, 30),
+			]);
+			let now = get_tz_datetime_dhm(1, 9, 0);
+			let request = OverrideRequest {
+				change_action: ChangeAction::Color { mirek: 300, brightness: 20 },
+				expiry: Expiry::ForMinutes(30),
+				fade_minutes: None,
+			};
+			let saved = schedule.add_override(&request, now).unwrap();
+			schedule.pause(String::from("test"), now, Some(5)).unwrap();
+
+			let mut restored = Schedule::new_for_test(schedule.raw_schedule.clone());
+			restored.restore_state(PersistedState {
+				overrides: vec![saved.clone()],
+				next_override_id: 1,
+				pause: schedule.pause.clone(),
+			});
+			assert_eq!(restored.overrides(), &[saved]);
+			assert_eq!(restored.next_override_id, 2);
+			assert_eq!(restored.update_and_get_action(&now).unwrap().1, ChangeAction::None);
+			assert_eq!(
+				restored.update_and_get_action(&get_tz_datetime_dhm(1, 9, 10)).unwrap().1,
+				ChangeAction::Color { mirek: 300, brightness: 20 },
+			);
+		}
 	}
 
 
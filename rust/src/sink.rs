@@ -0,0 +1,479 @@
+//! The outputs the apply loop sends the schedule's action to.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use chrono::DateTime;
+use chrono_tz::Tz;
+use reqwest::{Client, Url};
+use rocket::{
+    serde::{self, json::json},
+    tokio::time::Instant,
+};
+
+use crate::{
+    capabilities::{Capabilities, LightConfig},
+    hue::{HueClient, Light, LightUpdate, ResourceUpdate},
+    metrics::error_kind,
+    schedule::ChangeAction,
+};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Events for our own update can trail behind it, so they aren't compared until this long after sending.
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+/// The bridge reports brightness with more precision than we send it.
+const ALLOWED_BRIGHTNESS_DIFF: f64 = 1.0;
+
+/// The room, zone or group an action is meant for, and what's been declared about its lights.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SinkTarget {
+    /// Empty when only sinks that don't need a group are used, such as MQTT with its own topics.
+    pub group: String,
+    pub lights: Vec<LightConfig>,
+}
+
+impl fmt::Display for SinkTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.group.as_str() {
+            "" => write!(f, "the lights"),
+            group => write!(f, "{group}"),
+        }
+    }
+}
+
+/// What an event from a lighting system means for the group being applied to.
+#[derive(Debug, PartialEq)]
+pub enum Observation {
+    Unrelated,
+    /// Someone set the lights to something other than what was last sent.
+    ManualChange(String),
+    /// The group was switched off, then back on.
+    Cycled,
+}
+
+/// A lighting system, or anything else that wants to follow the schedule.
+///
+/// The apply loop skips actions a sink was already sent, so implementations send whatever they're given.
+#[rocket::async_trait]
+pub trait LightSink: Send {
+    fn kind(&self) -> SinkKind;
+
+    /// Returns whether anything was sent, which it usually isn't for `ChangeAction::None`.
+    async fn apply(&mut self, change_action: &ChangeAction, target: &SinkTarget) -> anyhow::Result<bool>;
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum SinkKind {
+    Hue,
+    Mqtt,
+    Webhook,
+    Log,
+}
+
+impl fmt::Display for SinkKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SinkKind::Hue => write!(f, "hue"),
+            SinkKind::Mqtt => write!(f, "mqtt"),
+            SinkKind::Webhook => write!(f, "webhook"),
+            SinkKind::Log => write!(f, "log"),
+        }
+    }
+}
+
+impl FromStr for SinkKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hue" => Ok(SinkKind::Hue),
+            "mqtt" => Ok(SinkKind::Mqtt),
+            "webhook" => Ok(SinkKind::Webhook),
+            "log" => Ok(SinkKind::Log),
+            _ => Err(anyhow!("Expected hue, mqtt, webhook or log, not {s}.")),
+        }
+    }
+}
+
+/// How a sink has been doing, for `/debug`.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct SinkStatus {
+    pub kind: SinkKind,
+    pub last_action: Option<ChangeAction>,
+    pub last_sent_at: Option<DateTime<Tz>>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Tz>>,
+    pub consecutive_failures: u32,
+}
+
+impl SinkStatus {
+    fn new(kind: SinkKind) -> Self {
+        SinkStatus { kind, last_action: None, last_sent_at: None, last_error: None, last_error_at: None, consecutive_failures: 0 }
+    }
+}
+
+/// Every sink's status, shared between the apply loop, `/debug` and `/metrics`.
+#[derive(Debug, Clone, Default)]
+pub struct SinkStatuses {
+    statuses: Arc<Mutex<Vec<SinkStatus>>>,
+    /// Failures since startup, by `error_kind`.
+    failure_counts: Arc<Mutex<BTreeMap<&'static str, u64>>>,
+}
+
+impl SinkStatuses {
+    /// Starts tracking a sink, returning the index to report its results under.
+    pub fn register(&self, kind: SinkKind) -> usize {
+        let mut statuses = self.statuses.lock().unwrap();
+        statuses.push(SinkStatus::new(kind));
+        statuses.len() - 1
+    }
+
+    /// `sent` is whether the sink actually sent anything for `change_action`.
+    pub fn succeeded(&self, index: usize, now: DateTime<Tz>, change_action: &ChangeAction, sent: bool) {
+        if let Some(status) = self.statuses.lock().unwrap().get_mut(index) {
+            status.last_action = Some(change_action.clone());
+            if sent {
+                status.last_sent_at = Some(now);
+            }
+            status.consecutive_failures = 0;
+        }
+    }
+
+    pub fn failed(&self, index: usize, now: DateTime<Tz>, error: &anyhow::Error) {
+        if let Some(status) = self.statuses.lock().unwrap().get_mut(index) {
+            status.last_error = Some(format!("{error:#}"));
+            status.last_error_at = Some(now);
+            status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+        }
+        *self.failure_counts.lock().unwrap().entry(error_kind(error)).or_default() += 1;
+    }
+
+    pub fn failure_counts(&self) -> BTreeMap<&'static str, u64> {
+        self.failure_counts.lock().unwrap().clone()
+    }
+
+    pub fn all(&self) -> Vec<SinkStatus> {
+        self.statuses.lock().unwrap().clone()
+    }
+}
+
+/// Only logs what would have been sent, for trying out a schedule without touching any lights.
+pub struct LogSink;
+
+#[rocket::async_trait]
+impl LightSink for LogSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Log
+    }
+
+    async fn apply(&mut self, change_action: &ChangeAction, target: &SinkTarget) -> anyhow::Result<bool> {
+        if change_action == &ChangeAction::None {
+            return Ok(false);
+        }
+        info!("Would set {target} to {change_action:?}.");
+        Ok(true)
+    }
+}
+
+/// POSTs `{"group": ..., "change_action": ...}` to `WEBHOOK_URL`.
+pub struct WebhookSink {
+    client: Client,
+    url: Url,
+}
+
+impl WebhookSink {
+    pub fn from_env(env_url_var: &str) -> anyhow::Result<Self> {
+        let url = env::var(env_url_var).context(format!("{env_url_var} is required for the webhook sink."))?;
+        Ok(WebhookSink {
+            client: Client::builder().timeout(WEBHOOK_TIMEOUT).build().context("Unable to create the webhook HTTP client")?,
+            url: Url::parse(&url).context(format!("Invalid {env_url_var}"))?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl LightSink for WebhookSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Webhook
+    }
+
+    async fn apply(&mut self, change_action: &ChangeAction, target: &SinkTarget) -> anyhow::Result<bool> {
+        if change_action == &ChangeAction::None {
+            return Ok(false);
+        }
+        let response = self.client
+            .post(self.url.clone())
+            .json(&json!({ "group": target.group, "change_action": change_action }))
+            .send()
+            .await
+            .context(format!("POST {} failed", self.url))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("POST {} returned {}", self.url, response.status()));
+        }
+        Ok(true)
+    }
+}
+
+/// The bridge resources that make up a room or zone.
+#[derive(Debug, Clone)]
+struct HueGroup {
+    name: String,
+    grouped_light: String,
+    lights: Vec<Light>,
+}
+
+/// What the Hue sink last sent, for the detector to compare the bridge's events against.
+#[derive(Debug, Default)]
+struct HueSent {
+    group: Option<HueGroup>,
+    action: Option<ChangeAction>,
+    /// What each light was last told to do.
+    expected: HashMap<String, LightUpdate>,
+    at: Option<Instant>,
+}
+
+/// Sends actions to a room or zone on the Hue bridge.
+pub struct HueSink {
+    hue: HueClient,
+    group: Option<HueGroup>,
+    sent: Arc<Mutex<HueSent>>,
+}
+
+impl HueSink {
+    /// Also returns a detector for changes to the lights that this sink didn't make.
+    pub fn new(hue: HueClient) -> (Self, ManualChangeDetector) {
+        let sent = Arc::new(Mutex::new(HueSent::default()));
+        (HueSink { hue, group: None, sent: sent.clone() }, ManualChangeDetector { sent, switched_off: false })
+    }
+
+    async fn group(&mut self, name: &str) -> anyhow::Result<&HueGroup> {
+        if self.group.as_ref().is_none_or(|group| group.name != name) {
+            let group = self.hue.group(name).await?;
+            let grouped_light = group.grouped_light().context(format!("{name} has no grouped_light."))?.to_string();
+            let lights = self.hue.lights().await?.into_iter().filter(|l| group.contains(l)).collect();
+            self.group = Some(HueGroup { name: name.to_string(), grouped_light, lights });
+        }
+        self.group.as_ref().context("Group was not resolved.")
+    }
+
+    /// Sends one update to the whole group when every light can take it, or a tailored one to each light otherwise.
+    async fn send(&mut self, change_action: &ChangeAction, target: &SinkTarget) -> anyhow::Result<HashMap<String, LightUpdate>> {
+        let hue = self.hue.clone();
+        let group = self.group(&target.group).await?;
+        let updates: HashMap<String, LightUpdate> = group.lights
+            .iter()
+            .filter_map(|light| Some((light.id.clone(), Capabilities::of(light, &target.lights).update_for(change_action)?)))
+            .collect();
+
+        let mut others = updates.values();
+        let first = others.next();
+        match first {
+            Some(first) if others.any(|update| update != first) => {
+                for (id, update) in &updates {
+                    hue.update_light(id, update).await?;
+                }
+            },
+            _ => {
+                let update = match first {
+                    Some(update) => update.clone(),
+                    None => LightUpdate::from_action(change_action).context("Nothing to send.")?,
+                };
+                hue.update_grouped_light(&group.grouped_light, &update).await?;
+            },
+        }
+        Ok(updates)
+    }
+}
+
+#[rocket::async_trait]
+impl LightSink for HueSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Hue
+    }
+
+    async fn apply(&mut self, change_action: &ChangeAction, target: &SinkTarget) -> anyhow::Result<bool> {
+        if change_action == &ChangeAction::None {
+            let mut sent = self.sent.lock().unwrap();
+            sent.action = Some(ChangeAction::None);
+            sent.expected.clear();
+            return Ok(false);
+        }
+
+        match self.send(change_action, target).await {
+            Ok(expected) => {
+                *self.sent.lock().unwrap() = HueSent {
+                    group: self.group.clone(),
+                    action: Some(change_action.clone()),
+                    expected,
+                    at: Some(Instant::now()),
+                };
+                Ok(true)
+            },
+            Err(e) => {
+                // The group may have been recreated, so look it up again next time.
+                self.group = None;
+                self.sent.lock().unwrap().group = None;
+                Err(e)
+            },
+        }
+    }
+}
+
+/// Compares updates reported by the bridge's event stream against what the Hue sink last sent.
+pub struct ManualChangeDetector {
+    sent: Arc<Mutex<HueSent>>,
+    switched_off: bool,
+}
+
+impl ManualChangeDetector {
+    pub fn observe(&mut self, update: &ResourceUpdate) -> Observation {
+        let sent = self.sent.lock().unwrap();
+        let Some(group) = &sent.group else { return Observation::Unrelated };
+        let is_group = update.id == group.grouped_light;
+        if !is_group && !group.lights.iter().any(|light| light.id == update.id) {
+            return Observation::Unrelated;
+        }
+
+        if let (Some(on), true) = (&update.on, is_group) {
+            if !on.on {
+                self.switched_off = true;
+            } else if self.switched_off {
+                self.switched_off = false;
+                return Observation::Cycled;
+            }
+        }
+
+        if sent.at.is_some_and(|at| at.elapsed() < SETTLE_TIME) {
+            return Observation::Unrelated;
+        }
+        let Some(ChangeAction::Color { brightness, .. }) = &sent.action else {
+            return Observation::Unrelated;
+        };
+        // The group reports the average of its lights, so only its brightness can be compared.
+        let expected = sent.expected.get(&update.id);
+        let expected_brightness = expected.and_then(|e| e.dimming.as_ref()).map_or(f64::from(*brightness), |d| d.brightness);
+        let expected_mirek = expected.and_then(|e| e.color_temperature.as_ref()).and_then(|c| c.mirek);
+
+        if let Some(dimming) = &update.dimming {
+            if (dimming.brightness - expected_brightness).abs() > ALLOWED_BRIGHTNESS_DIFF {
+                return Observation::ManualChange(format!("{} {} brightness set to {}", update.rtype, update.id, dimming.brightness));
+            }
+        }
+        if let (Some(color_temperature), Some(mirek), false) = (&update.color_temperature, expected_mirek, is_group) {
+            if color_temperature.mirek != Some(mirek) {
+                return Observation::ManualChange(format!("{} {} mirek set to {:?}", update.rtype, update.id, color_temperature.mirek));
+            }
+        }
+        Observation::Unrelated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use chrono_tz::US::Eastern;
+    use rocket::serde::json::{self, json};
+    use crate::{hue::ResourceUpdate, mock_bridge::MockBridge, schedule::ChangeAction};
+    use super::{HueSink, LightSink, Observation, SinkKind, SinkStatuses, SinkTarget};
+
+    fn living_room() -> SinkTarget {
+        SinkTarget { group: String::from("Living room"), lights: Vec::new() }
+    }
+
+    fn update(value: rocket::serde::json::Value) -> ResourceUpdate {
+        json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_statuses_track_each_sink() {
+        let statuses = SinkStatuses::default();
+        let hue = statuses.register(SinkKind::Hue);
+        let log = statuses.register(SinkKind::Log);
+        let now = Eastern.with_ymd_and_hms(1999, 1, 1, 10, 0, 0).unwrap();
+        let color = ChangeAction::Color { mirek: 300, brightness: 50 };
+
+        statuses.failed(hue, now, &anyhow::anyhow!("unreachable"));
+        statuses.failed(hue, now, &anyhow::anyhow!("unreachable"));
+        statuses.succeeded(log, now, &color, true);
+
+        let all = statuses.all();
+        assert_eq!(all[0].consecutive_failures, 2);
+        assert_eq!(all[0].last_error.as_deref(), Some("unreachable"));
+        assert_eq!(all[1].last_action, Some(color.clone()));
+        assert_eq!(statuses.failure_counts().get("rejected"), Some(&2));
+
+        statuses.succeeded(hue, now, &ChangeAction::None, false);
+        assert_eq!(statuses.all()[0].consecutive_failures, 0);
+        assert_eq!(statuses.all()[0].last_action, Some(ChangeAction::None));
+        // Nothing was sent for an action that leaves the lights alone.
+        assert_eq!(statuses.all()[0].last_sent_at, None);
+    }
+
+    #[rocket::async_test]
+    async fn test_hue_sink_tailors_mixed_lights() {
+        let bridge = MockBridge::start().await;
+        bridge.respond_living_room();
+        bridge.respond("GET", "/clip/v2/resource/light", 200, json!({
+            "errors": [],
+            "data": [{
+                "id": "light-1",
+                "owner": { "rid": "device-1", "rtype": "device" },
+                "metadata": { "name": "Lamp" },
+                "on": { "on": true },
+                "color_temperature": { "mirek": 300, "mirek_schema": { "mirek_minimum": 153, "mirek_maximum": 454 } },
+            }, {
+                "id": "light-2",
+                "owner": { "rid": "device-1", "rtype": "device" },
+                "metadata": { "name": "Bulb" },
+                "on": { "on": true },
+            }],
+        }));
+        bridge.respond("PUT", "/clip/v2/resource/light/light-1", 200, json!({ "errors": [], "data": [] }));
+        bridge.respond("PUT", "/clip/v2/resource/light/light-2", 200, json!({ "errors": [], "data": [] }));
+
+        let (mut sink, _) = HueSink::new(bridge.client());
+        assert!(sink.apply(&ChangeAction::Color { mirek: 500, brightness: 30 }, &living_room()).await.unwrap());
+
+        let requests = bridge.requests();
+        let put = |path: &str| requests.iter().find(|r| r.method == "PUT" && r.path == path).map(|r| r.json());
+        assert_eq!(put("/clip/v2/resource/light/light-1").unwrap()["color_temperature"]["mirek"], 454);
+        let bulb = put("/clip/v2/resource/light/light-2").unwrap();
+        assert_eq!(bulb["dimming"]["brightness"], 30.0);
+        assert!(bulb.get("color_temperature").is_none());
+        assert!(put("/clip/v2/resource/grouped_light/grouped-1").is_none());
+    }
+
+    #[rocket::async_test]
+    async fn test_detects_manual_changes() {
+        let bridge = MockBridge::start().await;
+        bridge.respond_living_room();
+
+        let (mut sink, mut detector) = HueSink::new(bridge.client());
+        sink.apply(&ChangeAction::Color { mirek: 300, brightness: 40 }, &living_room()).await.unwrap();
+        let manual = update(json!({ "id": "light-1", "type": "light", "dimming": { "brightness": 80.0 } }));
+
+        // Right after sending, the bridge may still be reporting the previous state.
+        assert_eq!(detector.observe(&manual), Observation::Unrelated);
+        detector.sent.lock().unwrap().at = None;
+
+        let echo = update(json!({ "id": "light-1", "type": "light", "dimming": { "brightness": 40.4 }, "color_temperature": { "mirek": 300 } }));
+        assert_eq!(detector.observe(&echo), Observation::Unrelated);
+        assert!(matches!(detector.observe(&manual), Observation::ManualChange(_)));
+        let other = update(json!({ "id": "light-2", "type": "light", "dimming": { "brightness": 80.0 } }));
+        assert_eq!(detector.observe(&other), Observation::Unrelated);
+
+        let off = update(json!({ "id": "grouped-1", "type": "grouped_light", "on": { "on": false } }));
+        let on = update(json!({ "id": "grouped-1", "type": "grouped_light", "on": { "on": true } }));
+        assert_eq!(detector.observe(&on), Observation::Unrelated);
+        assert_eq!(detector.observe(&off), Observation::Unrelated);
+        assert_eq!(detector.observe(&on), Observation::Cycled);
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use rocket::{
+    serde::{self, json::serde_json},
+    tokio::runtime::Handle,
+};
+
+use crate::{overrides::Override, pause::Pause};
+
+const STATE_FILE_NAME: &str = "state.json";
+
+/// Runtime changes that should survive a restart.
+#[derive(Debug, PartialEq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PersistedState {
+    pub overrides: Vec<Override>,
+    pub next_override_id: u64,
+    pub pause: Option<Pause>,
+}
+
+/// Snapshots `PersistedState` to a JSON file in the data dir. Without a data dir, nothing is kept.
+#[derive(Debug, Default)]
+pub struct StateStore {
+    path: Option<PathBuf>,
+    writer: Option<BackgroundWriter>,
+}
+
+impl StateStore {
+    /// Kept in `sub_dir` of the data dir, which may be empty.
+    pub fn from_env(env_data_dir_var: &str, sub_dir: &Path) -> Self {
+        match env::var(env_data_dir_var) {
+            Ok(dir) => Self::at(PathBuf::from(dir).join(sub_dir).join(STATE_FILE_NAME)),
+            Err(_) => {
+                info!("{env_data_dir_var} is not set, so overrides and pauses won't survive restarts.");
+                StateStore::default()
+            },
+        }
+    }
+
+    fn at(path: PathBuf) -> Self {
+        StateStore { writer: Some(BackgroundWriter::new(path.clone())), path: Some(path) }
+    }
+
+    /// Returns `None` if nothing has been stored yet.
+    pub fn load(&self) -> anyhow::Result<Option<PersistedState>> {
+        let Some(path) = &self.path else { return Ok(None) };
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let json = fs::read_to_string(path).context(format!("Unable to read {}", path.display()))?;
+        let state = serde_json::from_str(&json).context(format!("Unable to parse {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Only serializes `state` before returning. The file is written in the background.
+    pub fn save(&self, state: &PersistedState) -> anyhow::Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.write(serde_json::to_string_pretty(state)?);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    contents: Option<String>,
+    writing: bool,
+}
+
+/// Writes a file with `write_atomically` on the blocking thread pool, so callers holding the schedule's lock
+/// never wait on the disk. Writes happen one at a time, and one that hasn't started yet is replaced by a
+/// newer one, so the file always ends up with the latest contents.
+#[derive(Debug, Clone)]
+pub struct BackgroundWriter {
+    path: PathBuf,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl BackgroundWriter {
+    pub fn new(path: PathBuf) -> Self {
+        BackgroundWriter { path, pending: Arc::new(Mutex::new(Pending::default())) }
+    }
+
+    /// Outside of a Tokio runtime, e.g. in plain tests, this writes before returning.
+    pub fn write(&self, contents: String) {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.contents = Some(contents);
+            if pending.writing {
+                return;
+            }
+            pending.writing = true;
+        }
+
+        let writer = self.clone();
+        match Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(move || writer.drain())),
+            Err(_) => writer.drain(),
+        }
+    }
+
+    fn drain(&self) {
+        loop {
+            let contents = {
+                let mut pending = self.pending.lock().unwrap();
+                match pending.contents.take() {
+                    Some(contents) => contents,
+                    None => {
+                        pending.writing = false;
+                        return;
+                    },
+                }
+            };
+            if let Err(e) = write_atomically(&self.path, &contents) {
+                warn!("{e:#}");
+            }
+        }
+    }
+}
+
+/// Writes to a temporary file and renames it over `path`, so a crash never leaves a half-written file behind.
+pub fn write_atomically(path: &Path, contents: &str) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(format!("Unable to create {}", dir.display()))?;
+    }
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    fs::write(&temp_path, contents).context(format!("Unable to write {}", path.display()))?;
+    fs::rename(&temp_path, path).context(format!("Unable to replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use chrono::TimeZone;
+    use chrono_tz::US::Eastern;
+    use crate::{pause::Pause, schedule::ChangeAction, overrides::{Expiry, Override, OverrideRequest}};
+    use std::time::Duration;
+    use rocket::tokio::time::sleep;
+    use super::{BackgroundWriter, PersistedState, StateStore, STATE_FILE_NAME};
+
+    #[test]
+    fn test_state_round_trip() {
+        let dir = env::temp_dir().join(format!("rust-hue-store-test-{}", std::process::id()));
+        let store = StateStore::at(dir.join(STATE_FILE_NAME));
+        assert_eq!(store.load().unwrap(), None);
+
+        let now = Eastern.with_ymd_and_hms(1999, 1, 1, 10, 0, 0).unwrap();
+        let request = OverrideRequest {
+            change_action: ChangeAction::Color { mirek: 300, brightness: 20 },
+            expiry: Expiry::ForMinutes(60),
+            fade_minutes: None,
+        };
+        let state = PersistedState {
+            overrides: vec![Override::new(4, &request, now, now + chrono::TimeDelta::hours(1), chrono::TimeDelta::zero()).unwrap()],
+            next_override_id: 5,
+            pause: Some(Pause::new(String::from("test"), now, None).unwrap()),
+        };
+        store.save(&state).unwrap();
+
+        // Timestamps come back in UTC, but still refer to the same instants.
+        assert_eq!(store.load().unwrap(), Some(state));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_background_writes_end_with_the_latest() {
+        let dir = env::temp_dir().join(format!("rust-hue-writer-test-{}", std::process::id()));
+        let path = dir.join(STATE_FILE_NAME);
+        let writer = BackgroundWriter::new(path.clone());
+        for n in 0..50 {
+            writer.write(n.to_string());
+        }
+
+        for _ in 0..100 {
+            if fs::read_to_string(&path).is_ok_and(|contents| contents == "49") {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "49");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -5,12 +5,22 @@ use sunrise::sunrise_sunset;
 pub fn get_sunset_time(latitude: f64, longitude: f64, tz: Tz, now: &DateTime<Tz>) -> Result<DateTime<Tz>, String> {
     let (_, sunset_epoch) =
         sunrise_sunset(latitude, longitude, now.year(), now.month(), now.day());
-	if sunset_epoch == 0 {
-		return Err(format!("sunset_epoch is invalid ({sunset_epoch})."));
+	epoch_to_tz("sunset_epoch", sunset_epoch, tz)
+}
+
+pub fn get_sunrise_time(latitude: f64, longitude: f64, tz: Tz, now: &DateTime<Tz>) -> Result<DateTime<Tz>, String> {
+    let (sunrise_epoch, _) =
+        sunrise_sunset(latitude, longitude, now.year(), now.month(), now.day());
+	epoch_to_tz("sunrise_epoch", sunrise_epoch, tz)
+}
+
+fn epoch_to_tz(name: &str, epoch: i64, tz: Tz) -> Result<DateTime<Tz>, String> {
+	if epoch == 0 {
+		return Err(format!("{name} is invalid ({epoch})."));
 	}
 
-    match tz.timestamp_opt(sunset_epoch, 0).earliest() {
+    match tz.timestamp_opt(epoch, 0).earliest() {
         Some(local_datetime) => Ok(local_datetime),
-        None => Err(format!("Could not convert {sunset_epoch} to local datetime."))
+        None => Err(format!("Could not convert {epoch} to local datetime."))
     }
 }
@@ -0,0 +1,201 @@
+//! Tracing spans for every request and the schedule computation inside them, exported over OTLP and/or to a file.
+//! Without either configured, no subscriber is installed and spans cost next to nothing.
+
+use std::{
+    convert::Infallible,
+    env,
+    fs::{File, OpenOptions},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::StatusClass,
+    request::{self, FromRequest},
+    tokio::task,
+    Data, Orbit, Request, Response, Rocket,
+};
+use tracing::{field, Level, Span, Subscriber};
+use tracing_subscriber::{
+    filter::Targets,
+    fmt::{self, format::FmtSpan},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    Layer,
+};
+
+use crate::fairing::{request_id, route_name};
+
+const DEFAULT_SERVICE_NAME: &str = "rust-hue";
+
+/// The span `Telemetry` opens for every request, kept in the request's local cache. Handlers that do more than
+/// answer from memory take it as a guard and open their own spans inside it.
+#[derive(Clone)]
+pub struct RequestSpan(pub Span);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestSpan {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(request.local_cache(|| RequestSpan(Span::none())).clone())
+    }
+}
+
+/// Writes a JSON line for each span as it closes, including how long it was busy, nested in its parents.
+pub fn file_layer<S>(file: File) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fmt::layer()
+        .json()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_span_list(true)
+        .with_ansi(false)
+        .with_writer(Mutex::new(file))
+}
+
+/// Flushes spans that haven't been exported yet when Rocket shuts down.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// The exporter reads `OTEL_EXPORTER_OTLP_ENDPOINT` and friends itself, so `env_endpoint_var` only switches it on.
+    pub fn init_from_env(env_endpoint_var: &str, env_service_name_var: &str, env_file_var: &str) -> anyhow::Result<Self> {
+        let provider = match env::var(env_endpoint_var) {
+            Ok(_) => {
+                let exporter = SpanExporter::builder().with_http().build().context("Unable to create the OTLP exporter")?;
+                let mut resource = Resource::builder();
+                if env::var(env_service_name_var).is_err() {
+                    resource = resource.with_service_name(DEFAULT_SERVICE_NAME);
+                }
+                Some(SdkTracerProvider::builder().with_batch_exporter(exporter).with_resource(resource.build()).build())
+            },
+            Err(_) => None,
+        };
+        let file = match env::var(env_file_var) {
+            Ok(path) => Some(
+                OpenOptions::new().create(true).append(true).open(&path).context(format!("Unable to open {env_file_var} {path}"))?
+            ),
+            Err(_) => None,
+        };
+        if provider.is_none() && file.is_none() {
+            return Ok(Telemetry { provider: None });
+        }
+
+        let otel_layer = provider.as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(DEFAULT_SERVICE_NAME)));
+        // Only this crate's spans, rather than every poll of the HTTP clients and server.
+        let targets = Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::TRACE);
+        tracing_subscriber::registry()
+            .with(otel_layer.with_filter(targets.clone()))
+            .with(file.map(file_layer).with_filter(targets))
+            .try_init()
+            .context("Unable to install the tracing subscriber")?;
+        Ok(Telemetry { provider })
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Telemetry {
+    fn info(&self) -> Info {
+        Info { name: "Telemetry", kind: Kind::Request | Kind::Response | Kind::Shutdown }
+    }
+
+    /// The route isn't known until the request has been routed, so it's filled in with the status once it's answered.
+    /// The span closes when the request is dropped, which for streams is when they end.
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let span = tracing::info_span!(
+            "request",
+            otel.name = field::Empty,
+            otel.status_code = field::Empty,
+            method = request.method().as_str(),
+            route = field::Empty,
+            status = field::Empty,
+            request_id = %request_id(request),
+        );
+        request.local_cache(|| RequestSpan(span));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let span = &request.local_cache(|| RequestSpan(Span::none())).0;
+        let route = route_name(request);
+        span.record("otel.name", format!("{} {route}", request.method()));
+        span.record("route", route);
+        span.record("status", response.status().code);
+        if response.status().class() == StatusClass::ServerError {
+            span.record("otel.status_code", "ERROR");
+        }
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        let Some(provider) = self.provider.clone() else { return };
+        // Shutting down waits on the exporter's thread.
+        match task::spawn_blocking(move || provider.shutdown()).await {
+            Ok(Err(e)) => warn!("Unable to flush spans: {e}"),
+            Err(e) => warn!("Unable to flush spans: {e}"),
+            Ok(Ok(())) => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, sync::{Arc, Mutex}};
+    use rocket::{local::blocking::Client, serde::json::{serde_json, Value}};
+    use tracing::{span, Subscriber};
+    use tracing_subscriber::{layer::{Context, SubscriberExt}, registry::LookupSpan, Layer};
+    use super::{file_layer, RequestSpan, Telemetry};
+
+    type Opened = (&'static str, Option<&'static str>);
+
+    /// Each span's name and its parent's, as spans are opened.
+    #[derive(Clone, Default)]
+    struct Parents(Arc<Mutex<Vec<Opened>>>);
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Parents {
+        fn on_new_span(&self, _: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            self.0.lock().unwrap().push((span.name(), span.parent().map(|parent| parent.name())));
+        }
+    }
+
+    #[get("/traced")]
+    #[tracing::instrument(parent = &span.0, skip_all)]
+    fn traced(span: RequestSpan) {}
+
+    #[test]
+    fn test_request_spans() {
+        let path = env::temp_dir().join(format!("rust-hue-request-spans-test-{}.jsonl", std::process::id()));
+        let parents = Parents::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(file_layer(fs::File::create(&path).unwrap()))
+            .with(parents.clone());
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let rocket = rocket::build().attach(Telemetry { provider: None }).mount("/", routes![traced]);
+        let client = Client::untracked(rocket).unwrap();
+        client.get("/traced").dispatch();
+        client.get("/missing").dispatch();
+        drop(client);
+
+        assert_eq!(*parents.0.lock().unwrap(), [("request", None), ("traced", Some("request")), ("request", None)]);
+        // Each line is a span closing.
+        let requests: Vec<Value> = fs::read_to_string(&path).unwrap().lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["span"].clone())
+            .filter(|span| span["name"] == "request")
+            .collect();
+        assert_eq!(requests[0]["otel.name"], "GET /traced");
+        assert_eq!(requests[0]["status"], 200);
+        assert_eq!(requests[1]["route"], "unmatched");
+        assert_eq!(requests[1]["status"], 404);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
-use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use rocket::serde::{Deserialize, Deserializer};
 
 pub fn tz_now<T: TimeZone>(tz: &T) -> DateTime<T> {
 	let now = chrono::Utc::now().naive_local();
@@ -20,3 +22,15 @@ fn time_to_datetime_tz<T: TimeZone>(tz: &T, hour: u8, minute: u8, date: NaiveDat
 		None => Err(anyhow::anyhow!("Could not convert local ({naive_datetime}) to tz datetime.")),
 	}
 }
+
+/// Deserializes an RFC 3339 timestamp into UTC, since a `Tz` can't be recovered from its offset.
+/// Callers should convert it into their own timezone afterwards.
+pub fn deserialize_utc<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Tz>, D::Error> {
+	let datetime = DateTime::<FixedOffset>::deserialize(deserializer)?;
+	Ok(datetime.with_timezone(&Tz::UTC))
+}
+
+pub fn deserialize_utc_opt<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Tz>>, D::Error> {
+	let datetime = Option::<DateTime<FixedOffset>>::deserialize(deserializer)?;
+	Ok(datetime.map(|d| d.with_timezone(&Tz::UTC)))
+}
@@ -0,0 +1,95 @@
+//! Response shapes for `/v2`. Unlike v1's, every field is always present, as null when there's nothing
+//! to report, and enums are objects tagged with `type`, so clients can parse them without trying alternatives.
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+use rocket::serde;
+
+use crate::schedule::ChangeAction;
+
+/// Bumped when fields or `type`s are added. Within v2, nothing is ever removed or renamed.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[schema(as = v2::ActionType)]
+pub enum ActionType {
+    /// Leave the lights alone.
+    None,
+    Color,
+}
+
+/// What to set the lights to. `mirek` and `brightness` are null unless `type` is `color`.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+#[schema(as = v2::Action)]
+pub struct Action {
+    #[serde(rename = "type")]
+    pub kind: ActionType,
+    #[schema(required = true)]
+    pub mirek: Option<u16>,
+    #[schema(required = true)]
+    pub brightness: Option<u8>,
+}
+
+impl From<&ChangeAction> for Action {
+    fn from(change_action: &ChangeAction) -> Self {
+        match change_action {
+            ChangeAction::None => Action { kind: ActionType::None, mirek: None, brightness: None },
+            ChangeAction::Color { mirek, brightness } =>
+                Action { kind: ActionType::Color, mirek: Some(*mirek), brightness: Some(*brightness) },
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+#[schema(as = v2::Now)]
+pub struct Now {
+    pub schema_version: u32,
+    pub now: DateTime<Tz>,
+    pub change_action: Action,
+    pub just_updated: bool,
+    #[schema(required = true)]
+    pub override_id: Option<u64>,
+    pub paused: bool,
+    #[schema(required = true)]
+    pub paused_until: Option<DateTime<Tz>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use chrono_tz::US::Eastern;
+    use rocket::serde::json::{json, serde_json};
+    use crate::schedule::ChangeAction;
+    use super::{Action, Now, SCHEMA_VERSION};
+
+    #[test]
+    fn test_now() {
+        let mut now = Now {
+            schema_version: SCHEMA_VERSION,
+            now: Eastern.with_ymd_and_hms(1999, 1, 1, 18, 0, 0).unwrap(),
+            change_action: Action::from(&ChangeAction::Color { mirek: 370, brightness: 40 }),
+            just_updated: false,
+            override_id: None,
+            paused: false,
+            paused_until: None,
+        };
+        assert_eq!(serde_json::to_value(&now).unwrap(), json!({
+            "schema_version": 1,
+            "now": "1999-01-01T18:00:00-05:00",
+            "change_action": { "type": "color", "mirek": 370, "brightness": 40 },
+            "just_updated": false,
+            "override_id": null,
+            "paused": false,
+            "paused_until": null,
+        }));
+
+        now.change_action = Action::from(&ChangeAction::None);
+        assert_eq!(
+            serde_json::to_value(&now).unwrap()["change_action"],
+            json!({ "type": "none", "mirek": null, "brightness": null }),
+        );
+    }
+}
@@ -0,0 +1,391 @@
+//! Notifies other systems as the schedule passes items and sunset, or its config changes.
+
+use std::{
+    collections::VecDeque,
+    env,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, Url};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    serde::{self, json::{serde_json::{self, Map}, Value}},
+    tokio::{self, time::sleep},
+    Orbit, Rocket,
+};
+use sha2::Sha256;
+
+use crate::{
+    events::ChangeReason,
+    homes::{Home, Homes},
+    schedule::Crossing,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// How many deliveries `/webhooks/deliveries` remembers.
+const DELIVERY_LOG_LIMIT: usize = 200;
+/// Crossings further back than this are dropped rather than sent late, e.g. after the host slept.
+const MAX_CATCH_UP: chrono::TimeDelta = chrono::TimeDelta::hours(1);
+const EVENT_HEADER: &str = "X-Hue-Blend-Event";
+const DELIVERY_HEADER: &str = "X-Hue-Blend-Delivery";
+const SIGNATURE_HEADER: &str = "X-Hue-Blend-Signature";
+
+#[derive(Debug, PartialEq, Clone, Copy, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// The time of one of the schedule's items was reached.
+    ItemCrossed,
+    Sunset,
+    /// The schedule's config was replaced.
+    Reload,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Which events to send. All of them when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<WebhookEvent>,
+    /// JSON to send, where `{{name}}` in strings is replaced by the event's fields. A string that is only a
+    /// placeholder keeps the field's type. Without a body, the event's fields are sent as they are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+    /// The env var holding the key to sign bodies with, so the key itself stays out of the schedule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_env: Option<String>,
+}
+
+impl WebhookConfig {
+    pub fn warning(&self) -> Option<String> {
+        if let Err(e) = Url::parse(&self.url) {
+            return Some(format!("{} is not a valid URL: {e}", self.url));
+        }
+        match &self.secret_env {
+            Some(var) if env::var(var).is_err() => Some(format!("{var} is not set, so bodies to {} won't be signed.", self.url)),
+            _ => None,
+        }
+    }
+
+    fn wants(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+/// What a webhook is told about an event, and the values its body template can use.
+fn event_fields(home: &str, event: WebhookEvent, time: DateTime<Tz>, change: Option<Value>) -> Map<String, Value> {
+    let mut fields = Map::new();
+    fields.insert(String::from("home"), Value::String(home.to_string()));
+    fields.insert(String::from("event"), serde_json::to_value(event).unwrap_or(Value::Null));
+    fields.insert(String::from("time"), Value::String(time.to_rfc3339()));
+    if let Some(Value::Object(change)) = change {
+        fields.extend(change);
+    }
+    fields
+}
+
+/// Fills `{{name}}` placeholders in `template`'s strings. Unknown names are left empty.
+pub fn render(template: &Value, fields: &Map<String, Value>) -> Value {
+    match template {
+        Value::String(s) => {
+            let trimmed = s.trim();
+            if let Some(name) = trimmed.strip_prefix("{{").and_then(|rest| rest.strip_suffix("}}")) {
+                if !name.contains("{{") {
+                    return fields.get(name.trim()).cloned().unwrap_or(Value::Null);
+                }
+            }
+
+            let mut rendered = String::new();
+            let mut rest = s.as_str();
+            while let Some(start) = rest.find("{{") {
+                let Some(end) = rest[start..].find("}}") else { break };
+                rendered.push_str(&rest[..start]);
+                match fields.get(rest[start + 2..start + end].trim()) {
+                    Some(Value::String(value)) => rendered.push_str(value),
+                    Some(Value::Null) | None => {},
+                    Some(value) => rendered.push_str(&value.to_string()),
+                }
+                rest = &rest[start + end + 2..];
+            }
+            rendered.push_str(rest);
+            Value::String(rendered)
+        },
+        Value::Array(items) => Value::Array(items.iter().map(|item| render(item, fields)).collect()),
+        Value::Object(map) => Value::Object(map.iter().map(|(k, v)| (k.clone(), render(v, fields))).collect()),
+        other => other.clone(),
+    }
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// One event sent to one webhook, including every attempt at it.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Delivery {
+    pub id: u64,
+    /// The home whose schedule the event came from.
+    pub home: String,
+    pub event: WebhookEvent,
+    pub url: String,
+    pub created_at: DateTime<Tz>,
+    pub attempts: u32,
+    pub delivered: bool,
+    /// The status of the last response, if there was one.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct DeliveryLog {
+    deliveries: VecDeque<Delivery>,
+    next_id: u64,
+}
+
+/// Sends webhooks and keeps a log of how that went.
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    client: Client,
+    log: Arc<StdMutex<DeliveryLog>>,
+    retry_delay: Duration,
+    /// Looks up the secret named by a webhook's `secret_env`.
+    secret: fn(&str) -> Option<String>,
+}
+
+impl Webhooks {
+    /// Signs with secrets from the environment.
+    pub fn new() -> anyhow::Result<Self> {
+        Self::with_secrets(|var| env::var(var).ok())
+    }
+
+    fn with_secrets(secret: fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        Ok(Webhooks {
+            client: Client::builder().timeout(REQUEST_TIMEOUT).build()?,
+            log: Arc::new(StdMutex::new(DeliveryLog { deliveries: VecDeque::new(), next_id: 1 })),
+            retry_delay: INITIAL_RETRY_DELAY,
+            secret,
+        })
+    }
+
+    /// Most recent first.
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.log.lock().unwrap().deliveries.iter().rev().cloned().collect()
+    }
+
+    fn start_delivery(&self, home: &str, event: WebhookEvent, url: &str, now: DateTime<Tz>) -> u64 {
+        let mut log = self.log.lock().unwrap();
+        let id = log.next_id;
+        log.next_id += 1;
+        if log.deliveries.len() >= DELIVERY_LOG_LIMIT {
+            log.deliveries.pop_front();
+        }
+        log.deliveries.push_back(Delivery {
+            id, home: home.to_string(), event, url: url.to_string(), created_at: now, attempts: 0, delivered: false, status: None, error: None,
+        });
+        id
+    }
+
+    fn update_delivery(&self, id: u64, update: impl FnOnce(&mut Delivery)) {
+        if let Some(delivery) = self.log.lock().unwrap().deliveries.iter_mut().find(|d| d.id == id) {
+            update(delivery);
+        }
+    }
+
+    /// Sends `fields` to every one of `home`'s webhooks that wants `event`, each in the background.
+    pub fn dispatch(&self, home: &str, configs: &[WebhookConfig], event: WebhookEvent, fields: &Map<String, Value>, now: DateTime<Tz>) {
+        for config in configs.iter().filter(|config| config.wants(event)) {
+            let body = match &config.body {
+                Some(template) => render(template, fields),
+                None => Value::Object(fields.clone()),
+            };
+            let id = self.start_delivery(home, event, &config.url, now);
+            let secret = config.secret_env.as_deref().and_then(self.secret);
+            tokio::spawn(self.clone().deliver(id, event, config.url.clone(), body.to_string(), secret));
+        }
+    }
+
+    /// Retries failed attempts with a doubling delay, giving up after `MAX_ATTEMPTS`.
+    async fn deliver(self, id: u64, event: WebhookEvent, url: String, body: String, secret: Option<String>) {
+        let event_name = serde_json::to_value(event).ok().and_then(|v| v.as_str().map(String::from)).unwrap_or_default();
+        let mut delay = self.retry_delay;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut request = self.client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .header(EVENT_HEADER, &event_name)
+                .header(DELIVERY_HEADER, id.to_string())
+                .body(body.clone());
+            if let Some(secret) = &secret {
+                request = request.header(SIGNATURE_HEADER, sign(secret, body.as_bytes()));
+            }
+
+            let (status, error) = match request.send().await {
+                Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+                Ok(response) => (Some(response.status().as_u16()), Some(format!("Returned {}", response.status()))),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            let delivered = error.is_none();
+            self.update_delivery(id, |delivery| {
+                delivery.attempts = attempt;
+                delivery.delivered = delivered;
+                delivery.status = status;
+                delivery.error = error.clone();
+            });
+            if delivered {
+                return;
+            }
+
+            if attempt < MAX_ATTEMPTS {
+                sleep(delay).await;
+                delay = delay.saturating_mul(2);
+            } else {
+                warn!("Giving up on delivering {event_name} to {url} after {MAX_ATTEMPTS} attempts: {}", error.unwrap_or_default());
+            }
+        }
+    }
+}
+
+/// Watches every home's schedule for the events webhooks can subscribe to.
+pub struct WebhookNotifier;
+
+#[rocket::async_trait]
+impl Fairing for WebhookNotifier {
+    fn info(&self) -> Info {
+        Info { name: "Webhook notifier", kind: Kind::Liftoff }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let (Some(homes), Some(webhooks)) = (rocket.state::<Homes>(), rocket.state::<Webhooks>()) else {
+            error!("WebhookNotifier requires managed Homes and Webhooks.");
+            return;
+        };
+
+        for home in homes.iter() {
+            tokio::spawn(run(home.clone(), webhooks.clone()));
+        }
+    }
+}
+
+async fn run(home: Home, webhooks: Webhooks) {
+    let mut subscription = home.events.subscribe();
+    let mut last_checked = home.schedule.lock().await.now();
+
+    while let Some(reason) = subscription.wait().await {
+        let (now, crossings, configs) = {
+            let guard = home.schedule.lock().await;
+            let now = (*guard).now();
+            let from = last_checked.max(now - MAX_CATCH_UP);
+            (now, (*guard).crossings(&from, &now), (*guard).webhook_configs().to_vec())
+        };
+        last_checked = now;
+
+        if reason == ChangeReason::Reload {
+            let fields = event_fields(&home.id, WebhookEvent::Reload, now, None);
+            webhooks.dispatch(&home.id, &configs, WebhookEvent::Reload, &fields, now);
+        }
+        let crossings = match crossings {
+            Ok(crossings) => crossings,
+            Err(e) => {
+                warn!("Unable to check what the schedule for {home} passed for webhooks: {e:#}");
+                continue;
+            },
+        };
+        for crossing in crossings {
+            let (event, change) = match &crossing {
+                Crossing::Item { change, .. } => (WebhookEvent::ItemCrossed, serde_json::to_value(change).ok()),
+                Crossing::Sunset { .. } => (WebhookEvent::Sunset, None),
+            };
+            webhooks.dispatch(&home.id, &configs, event, &event_fields(&home.id, event, crossing.time(), change), now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chrono::TimeZone;
+    use chrono_tz::US::Eastern;
+    use rocket::{serde::json::{json, serde_json}, tokio::time::sleep};
+    use crate::mock_bridge::MockBridge;
+    use super::{event_fields, render, sign, WebhookConfig, WebhookEvent, Webhooks};
+
+    #[test]
+    fn test_render_template() {
+        let time = Eastern.with_ymd_and_hms(1999, 1, 1, 18, 0, 0).unwrap();
+        let fields = event_fields("cabin", WebhookEvent::ItemCrossed, time, Some(json!({ "action": "color", "mirek": 370, "brightness": 40 })));
+        let template = json!({
+            "text": "Lights at {{home}} going to {{ mirek }} mirek at {{time}}{{missing}}",
+            "mirek": "{{mirek}}",
+            "tags": ["{{event}}"],
+        });
+
+        assert_eq!(render(&template, &fields), json!({
+            "text": "Lights at cabin going to 370 mirek at 1999-01-01T18:00:00-05:00",
+            "mirek": 370,
+            "tags": ["item_crossed"],
+        }));
+    }
+
+    #[test]
+    fn test_sign() {
+        // From RFC 4231, test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_deliveries_are_signed_retried_and_logged() {
+        let receiver = MockBridge::start().await;
+        let mut webhooks = Webhooks::with_secrets(|var| (var == "NAS_SECRET").then(|| String::from("secret"))).unwrap();
+        webhooks.retry_delay = Duration::from_millis(10);
+
+        let configs = vec![
+            WebhookConfig {
+                url: format!("{}nas", receiver.base_url()),
+                events: vec![WebhookEvent::Sunset],
+                body: Some(json!({ "led": "dim", "at": "{{time}}" })),
+                secret_env: Some(String::from("NAS_SECRET")),
+            },
+            WebhookConfig { url: format!("{}missing", receiver.base_url()), events: Vec::new(), body: None, secret_env: None },
+        ];
+        receiver.respond("POST", "/nas", 204, json!(null));
+        let now = Eastern.with_ymd_and_hms(1999, 1, 1, 17, 0, 0).unwrap();
+        webhooks.dispatch("default", &configs, WebhookEvent::Sunset, &event_fields("default", WebhookEvent::Sunset, now, None), now);
+        webhooks.dispatch("default", &configs, WebhookEvent::Reload, &event_fields("default", WebhookEvent::Reload, now, None), now);
+
+        for _ in 0..100 {
+            if webhooks.deliveries().iter().all(|d| d.delivered || d.attempts == super::MAX_ATTEMPTS) {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+
+        let deliveries = webhooks.deliveries();
+        assert_eq!(deliveries.len(), 3);
+        let nas = deliveries.iter().find(|d| d.url.ends_with("nas")).unwrap();
+        assert!(nas.delivered);
+        assert_eq!((nas.attempts, nas.status), (1, Some(204)));
+        // The mock answers anything it doesn't know with a 404.
+        assert!(deliveries.iter().filter(|d| d.url.ends_with("missing")).all(|d| !d.delivered && d.attempts == super::MAX_ATTEMPTS));
+
+        let request = receiver.requests().into_iter().find(|r| r.path == "/nas").unwrap();
+        assert_eq!(request.json(), json!({ "led": "dim", "at": "1999-01-01T17:00:00-05:00" }));
+        assert_eq!(request.header("x-hue-blend-event"), Some("sunset"));
+        assert_eq!(request.header("x-hue-blend-signature"), Some(sign("secret", request.body.as_bytes()).as_str()));
+        let reload = receiver.requests().into_iter().find(|r| r.path == "/missing" && r.json()["event"] == "reload").unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&reload.body).unwrap()["time"], "1999-01-01T17:00:00-05:00");
+        assert_eq!(reload.json()["home"], "default");
+    }
+}
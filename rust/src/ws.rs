@@ -0,0 +1,213 @@
+use rocket::{
+    futures::{SinkExt, StreamExt},
+    response::{self, Responder},
+    serde::{self, json::{serde_json, Value}},
+    tokio::select,
+    Request, Shutdown,
+};
+use rocket_ws::{Channel, Message, WebSocket};
+
+use crate::{
+    actions::Actions,
+    audit::{AuditLog, Caller},
+    auth::BEARER_PROTOCOL,
+    events::StreamEvent,
+    homes::Home,
+    overrides::OverrideRequest,
+};
+
+/// Bumped whenever a message shape changes in a way old clients can't ignore.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+enum Command {
+    Ping,
+    ForceUpdate,
+    SetOverride(OverrideRequest),
+    /// Cancels a single override, or all of them if `override_id` is omitted.
+    CancelOverride { override_id: Option<u64> },
+    Pause { minutes: Option<u32>, by: Option<String> },
+    Resume { after_minutes: Option<u32> },
+}
+
+#[derive(Debug)]
+struct ClientMessage {
+    id: Option<String>,
+    command: Command,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+enum ServerPayload {
+    Hello,
+    ChangeAction(StreamEvent),
+    Error { error: String },
+    Ack {
+        id: Option<String>,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ServerMessage {
+    version: u32,
+    #[serde(flatten)]
+    payload: ServerPayload,
+}
+
+impl ServerMessage {
+    fn new(payload: ServerPayload) -> Self {
+        ServerMessage { version: PROTOCOL_VERSION, payload }
+    }
+
+    fn ack(id: Option<String>, result: anyhow::Result<()>) -> Self {
+        let (ok, error) = match result {
+            Ok(()) => (true, None),
+            Err(e) => (false, Some(e.to_string())),
+        };
+        Self::new(ServerPayload::Ack { id, ok, error })
+    }
+
+    fn to_message(&self) -> Message {
+        // Serializing these plain structs can't fail.
+        Message::Text(serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+/// Parses a client message. On failure, returns whatever `id` could be recovered so the error can be acked.
+fn parse_client_message(text: &str) -> Result<ClientMessage, (Option<String>, String)> {
+    let value: Value = serde_json::from_str(text).map_err(|e| (None, format!("Invalid JSON: {e}")))?;
+    let id = value.get("id").and_then(Value::as_str).map(String::from);
+
+    match value.get("version").and_then(Value::as_u64) {
+        Some(v) if v == PROTOCOL_VERSION as u64 => {},
+        Some(v) => return Err((id, format!("Unsupported protocol version {v}, expected {PROTOCOL_VERSION}."))),
+        None => return Err((id, String::from("Missing protocol `version`."))),
+    }
+
+    match serde_json::from_value::<Command>(value) {
+        Ok(command) => Ok(ClientMessage { id, command }),
+        Err(e) => Err((id, format!("Invalid command: {e}"))),
+    }
+}
+
+async fn run_command(command: Command, actions: &Actions<'_>) -> anyhow::Result<()> {
+    match command {
+        Command::Ping => Ok(()),
+        Command::ForceUpdate => actions.force_update().await,
+        Command::SetOverride(request) => actions.add_override(&request).await.map(|_| ()),
+        Command::CancelOverride { override_id } => actions.cancel_overrides(override_id).await.map(|_| ()),
+        Command::Pause { minutes, by } => actions.pause(minutes, by).await.map(|_| ()),
+        Command::Resume { after_minutes } => actions.resume(after_minutes).await.map(|_| ()),
+    }
+}
+
+/// The switch to the control channel, accepting the `bearer` subprotocol when the token was offered that way.
+pub struct Upgrade {
+    pub channel: Channel<'static>,
+    pub bearer_protocol: bool,
+}
+
+impl<'r> Responder<'r, 'static> for Upgrade {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = self.channel.respond_to(request)?;
+        if self.bearer_protocol {
+            response.set_raw_header("Sec-WebSocket-Protocol", BEARER_PROTOCOL);
+        }
+        Ok(response)
+    }
+}
+
+pub fn channel(
+    ws: WebSocket,
+    caller: Caller,
+    home: Home,
+    audit: AuditLog,
+    mut shutdown: Shutdown,
+) -> rocket_ws::Channel<'static> {
+    let mut subscription = home.events.stream();
+
+    ws.channel(move |mut stream| Box::pin(async move {
+        stream.send(ServerMessage::new(ServerPayload::Hello).to_message()).await?;
+
+        loop {
+            select! {
+                outcome = subscription.next() => {
+                    let payload = match outcome {
+                        None => break,
+                        Some(Ok(event)) => ServerPayload::ChangeAction(event),
+                        Some(Err(error)) => ServerPayload::Error { error },
+                    };
+                    stream.send(ServerMessage::new(payload).to_message()).await?;
+                },
+                incoming = stream.next() => {
+                    let text = match incoming {
+                        None | Some(Ok(Message::Close(_))) => break,
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => return Err(e),
+                    };
+
+                    let actions = Actions { caller: &caller, home: &home, audit: &audit };
+                    let reply = match parse_client_message(&text) {
+                        Ok(message) => ServerMessage::ack(message.id, run_command(message.command, &actions).await),
+                        Err((id, error)) => ServerMessage::ack(id, Err(anyhow::Error::msg(error))),
+                    };
+                    stream.send(reply.to_message()).await?;
+                },
+                _ = &mut shutdown => break,
+            }
+        }
+
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{overrides::Expiry, schedule::ChangeAction};
+    use super::{parse_client_message, Command};
+
+    #[test]
+    fn test_parse_client_message() {
+        let message = parse_client_message(r#"{"version": 1, "id": "a", "type": "force_update"}"#).unwrap();
+        assert_eq!(message.id, Some(String::from("a")));
+        assert!(matches!(message.command, Command::ForceUpdate));
+
+        let message = parse_client_message(r#"{"version": 1, "type": "ping"}"#).unwrap();
+        assert_eq!(message.id, None);
+        assert!(matches!(message.command, Command::Ping));
+    }
+
+    #[test]
+    fn test_parse_override_commands() {
+        let message = parse_client_message(r#"{
+            "version": 1,
+            "type": "set_override",
+            "change_action": {"color": {"mirek": 300, "brightness": 20}},
+            "expiry": {"for_minutes": 120}
+        }"#).unwrap();
+        let Command::SetOverride(request) = message.command else { panic!("Expected set_override.") };
+        assert_eq!(request.change_action, ChangeAction::Color { mirek: 300, brightness: 20 });
+        assert_eq!(request.expiry, Expiry::ForMinutes(120));
+        assert_eq!(request.fade_minutes, None);
+
+        let message = parse_client_message(r#"{"version": 1, "type": "cancel_override"}"#).unwrap();
+        assert!(matches!(message.command, Command::CancelOverride { override_id: None }));
+    }
+
+    #[test]
+    fn test_parse_client_message_invalid() {
+        assert_eq!(parse_client_message("not json").unwrap_err().0, None);
+        assert_eq!(
+            parse_client_message(r#"{"version": 2, "id": "b", "type": "ping"}"#).unwrap_err().0,
+            Some(String::from("b")),
+        );
+        assert!(parse_client_message(r#"{"id": "c", "type": "ping"}"#).is_err());
+        assert!(parse_client_message(r#"{"version": 1, "type": "dance"}"#).is_err());
+    }
+}